@@ -39,15 +39,40 @@ export class Client
         const instance = new Client();
         return instance;
     }
+    /* Double-submit token set along with the refresh cookie. The server */
+    /* also sends it in a header, the only way to read it when the API is */
+    /* on another origin. */
+    private static csrfHeader: string | undefined = undefined;
+    private static csrfToken(): string | undefined
+    {
+        if (Client.csrfHeader)
+            return Client.csrfHeader;
+        const cookie = document.cookie
+            .split("; ")
+            .find((cookie) => cookie.startsWith("csrf="));
+        return cookie?.substring("csrf=".length);
+    }
+    private static storeCsrfToken(response: Response): void
+    {
+        const csrfToken = response.headers.get("X-CSRF-Token");
+        if (csrfToken)
+            Client.csrfHeader = csrfToken;
+    }
     public static async fetchRefresh(): Promise<void>
     {
+        const headers: Headers = new Headers();
+        const csrfToken = Client.csrfToken();
+        if (csrfToken)
+            headers.set("X-CSRF-Token", csrfToken);
         const options: RequestInit =
         {
             method: "POST",
+            headers: headers,
             credentials: "include"
         };
         const response =
             await fetch(Client.env.SNOWPACK_PUBLIC_REFRESH_ENDPOINT, options);
+        Client.storeCsrfToken(response);
         if (response.ok)
             Client.token = (await response.json()).token;
     }
@@ -61,14 +86,16 @@ export class Client
         headers.set("Content-Type", "application/json");
         if (Client.token)
             headers.set("Authorization", "Bearer " + Client.token);
-        const fetchOptions =
+        const fetchOptions: RequestInit =
         {
             method: "POST",
             headers: headers,
-            body: JSON.stringify(data)
+            body: JSON.stringify(data),
+            credentials: "include"
         };
         const response =
             await fetch(Client.env.SNOWPACK_PUBLIC_GRAPHQL_ENDPOINT, fetchOptions);
+        Client.storeCsrfToken(response);
         return await response.json();
     }
     public hydrate(element: React.ReactElement): void
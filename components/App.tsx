@@ -7,6 +7,8 @@ import { Spinner } from "./Loading.tsx";
 import * as Index from "./Pages/Index.tsx";
 import * as Login from "./Pages/Login.tsx";
 import * as Register from "./Pages/Register.tsx";
+import * as ResetPassword from "./Pages/ResetPassword.tsx";
+import * as VerifyEmail from "./Pages/VerifyEmail.tsx";
 import * as Error from "./Pages/Error.tsx";
 
 export default function App()
@@ -35,6 +37,8 @@ export default function App()
                     <ReactRouter.Route path="/" element={<Index.Helmet />} />
                     <ReactRouter.Route path="/login" element={<Login.Helmet />} />
                     <ReactRouter.Route path="/register" element={<Register.Helmet />} />
+                    <ReactRouter.Route path="/reset-password" element={<ResetPassword.Helmet />} />
+                    <ReactRouter.Route path="/verify-email" element={<VerifyEmail.Helmet />} />
                     <ReactRouter.Route path="*" element={<Error.Helmet code={404} text="not found" />} />
                 </ReactRouter.Routes>
                 <Suspense fallback={<Spinner padding="5rem" />}>
@@ -42,6 +46,8 @@ export default function App()
                         <ReactRouter.Route path="/" element={<Index.Page />} />
                         <ReactRouter.Route path="/login" element={<Login.Page />} />
                         <ReactRouter.Route path="/register" element={<Register.Page />} />
+                        <ReactRouter.Route path="/reset-password" element={<ResetPassword.Page />} />
+                        <ReactRouter.Route path="/verify-email" element={<VerifyEmail.Page />} />
                         <ReactRouter.Route path="*" element={<Error.Page code={404} text="not found" />} />
                     </ReactRouter.Routes>
                 </Suspense>
@@ -18,9 +18,7 @@ export default function Register()
 
     const mutation = graphql`
             mutation RegisterMutation($email: String!, $password: String!) {
-                createUser(email: $email, password: $password) {
-                    id
-                }
+                createUser(email: $email, password: $password)
             }
         `;
 
@@ -0,0 +1,84 @@
+import * as React from "react";
+import Relay from "react-relay/hooks";
+import { graphql } from "relay-runtime";
+import * as ReactRouter from "react-router-dom";
+
+import { environment, Environment, Console, useStartLoading } from "../../Core/Core.tsx";
+
+interface Value
+{
+    value: string;
+}
+
+/* Landing page of the link mailed by requestPasswordReset. */
+export default function ResetPassword()
+{
+    const [password, setPassword] = React.useState("");
+    const [failed, setFailed] = React.useState(false);
+
+    const mutation = graphql`
+            mutation ResetPasswordMutation($token: String!, $newPassword: String!) {
+                resetPassword(token: $token, newPassword: $newPassword)
+            }
+        `;
+
+    const [commit, isInFlight] = Relay.useMutation(mutation);
+    const navigate = ReactRouter.useNavigate();
+    const location = ReactRouter.useLocation();
+    const token = new URLSearchParams(location.search).get("token") ?? "";
+
+    function onSubmit(event: React.FormEvent<HTMLFormElement>): void
+    {
+        event.preventDefault();
+        switch (environment())
+        {
+            case Environment.SERVER:
+                return;
+            case Environment.CLIENT:
+                break;
+        }
+
+
+        const variables =
+        {
+            "token": token,
+            "newPassword": password
+        };
+
+        const onCompleted = function (data: unknown)
+        {
+            Console.log(data);
+            navigate("/login", { state: { redirected: true, loading: useStartLoading() } });
+        };
+
+        const onError = function (error: Error)
+        {
+            Console.error(error);
+            setPassword("");
+            setFailed(true);
+        };
+
+        commit({ variables: variables, onCompleted: onCompleted, onError: onError });
+    }
+    const element =
+        <div className="page">
+            <div className="form-wrapper">
+                <form onSubmit={onSubmit}>
+                    {failed && <h3>This link is invalid or has expired</h3>}
+                    <div className="form-item-wrapper">
+                        <input
+                            type="password" id="password" name="password" required
+                            placeholder="new password"
+                            onChange={function (event) { setPassword((event.target as (typeof event.target & Value)).value.trim()); }}
+                            value={password}
+                        />
+                    </div>
+                    <div className="form-item-wrapper">
+                        <input type="submit" className="button shadow" value="Reset password" disabled={isInFlight || !token} />
+                    </div>
+                </form>
+            </div>
+            <p className="copyinfo">© {new Date().getFullYear()}</p>
+        </div>;
+    return element;
+}
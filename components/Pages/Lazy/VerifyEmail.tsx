@@ -0,0 +1,81 @@
+import * as React from "react";
+import Relay from "react-relay/hooks";
+import { graphql } from "relay-runtime";
+import * as ReactRouter from "react-router-dom";
+
+import { environment, Environment, Console } from "../../Core/Core.tsx";
+
+enum Status
+{
+    PENDING,
+    VERIFIED,
+    FAILED
+}
+
+/* Landing page of the links mailed to verify an address or to move the */
+/* account to a new one. The token is redeemed as soon as the page loads. */
+export default function VerifyEmail()
+{
+    const [status, setStatus] = React.useState(Status.PENDING);
+
+    const mutation = graphql`
+            mutation VerifyEmailMutation($token: String!) {
+                verifyEmail(token: $token)
+            }
+        `;
+
+    const [commit] = Relay.useMutation(mutation);
+    const location = ReactRouter.useLocation();
+    const token = new URLSearchParams(location.search).get("token") ?? "";
+
+    function verify(): void
+    {
+        switch (environment())
+        {
+            case Environment.SERVER:
+                return;
+            case Environment.CLIENT:
+                break;
+        }
+        if (!token)
+        {
+            setStatus(Status.FAILED);
+            return;
+        }
+
+        const onCompleted = function (data: unknown)
+        {
+            Console.log(data);
+            setStatus(Status.VERIFIED);
+        };
+
+        const onError = function (error: Error)
+        {
+            Console.error(error);
+            setStatus(Status.FAILED);
+        };
+
+        commit({ variables: { "token": token }, onCompleted: onCompleted, onError: onError });
+    }
+    React.useEffect(verify, [token]);
+
+    const text = (function ()
+    {
+        switch (status)
+        {
+            case Status.PENDING:
+                return "Verifying your email address...";
+            case Status.VERIFIED:
+                return "Your email address is verified";
+            case Status.FAILED:
+                return "This link is invalid or has expired";
+        }
+    })();
+    const element =
+        <div className="page">
+            <h3>{text}</h3>
+            {status === Status.VERIFIED && <ReactRouter.Link to="/login">Log in</ReactRouter.Link>}
+            <p className="copyinfo">© {new Date().getFullYear()}</p>
+        </div>;
+    return element;
+}
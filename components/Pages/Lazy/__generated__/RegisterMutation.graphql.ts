@@ -9,9 +9,7 @@ export type RegisterMutationVariables = {
     password: string;
 };
 export type RegisterMutationResponse = {
-    readonly createUser: {
-        readonly id: string;
-    };
+    readonly createUser: boolean;
 };
 export type RegisterMutation = {
     readonly response: RegisterMutationResponse;
@@ -25,9 +23,7 @@ mutation RegisterMutation(
   $email: String!
   $password: String!
 ) {
-  createUser(email: $email, password: $password) {
-    id
-  }
+  createUser(email: $email, password: $password)
 }
 */
 
@@ -59,19 +55,8 @@ v1 = [
         "variableName": "password"
       }
     ],
-    "concreteType": "User",
-    "kind": "LinkedField",
+    "kind": "ScalarField",
     "name": "createUser",
-    "plural": false,
-    "selections": [
-      {
-        "alias": null,
-        "args": null,
-        "kind": "ScalarField",
-        "name": "id",
-        "storageKey": null
-      }
-    ],
     "storageKey": null
   }
 ];
@@ -93,14 +78,14 @@ return {
     "selections": (v1/*: any*/)
   },
   "params": {
-    "cacheID": "899844ef14adf3ec51e9ffb7b395cebe",
+    "cacheID": "dd0439db0331bd4a82e590b02046be46",
     "id": null,
     "metadata": {},
     "name": "RegisterMutation",
     "operationKind": "mutation",
-    "text": "mutation RegisterMutation(\n  $email: String!\n  $password: String!\n) {\n  createUser(email: $email, password: $password) {\n    id\n  }\n}\n"
+    "text": "mutation RegisterMutation(\n  $email: String!\n  $password: String!\n) {\n  createUser(email: $email, password: $password)\n}\n"
   }
 };
 })();
-(node as any).hash = 'c86d7171315ab74fcda175dd47c529ee';
+(node as any).hash = 'e889645f50ac3f7bb77ffaa1c6123c90';
 export default node;
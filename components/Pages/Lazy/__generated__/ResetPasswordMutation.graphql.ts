@@ -0,0 +1,91 @@
+// deno-lint-ignore-file 
+/* tslint:disable */
+/* eslint-disable */
+// @ts-nocheck
+
+import { ConcreteRequest } from "relay-runtime";
+export type ResetPasswordMutationVariables = {
+    token: string;
+    newPassword: string;
+};
+export type ResetPasswordMutationResponse = {
+    readonly resetPassword: boolean;
+};
+export type ResetPasswordMutation = {
+    readonly response: ResetPasswordMutationResponse;
+    readonly variables: ResetPasswordMutationVariables;
+};
+
+
+
+/*
+mutation ResetPasswordMutation(
+  $token: String!
+  $newPassword: String!
+) {
+  resetPassword(token: $token, newPassword: $newPassword)
+}
+*/
+
+const node: ConcreteRequest = (function(){
+var v0 = [
+  {
+    "defaultValue": null,
+    "kind": "LocalArgument",
+    "name": "token"
+  },
+  {
+    "defaultValue": null,
+    "kind": "LocalArgument",
+    "name": "newPassword"
+  }
+],
+v1 = [
+  {
+    "alias": null,
+    "args": [
+      {
+        "kind": "Variable",
+        "name": "token",
+        "variableName": "token"
+      },
+      {
+        "kind": "Variable",
+        "name": "newPassword",
+        "variableName": "newPassword"
+      }
+    ],
+    "kind": "ScalarField",
+    "name": "resetPassword",
+    "storageKey": null
+  }
+];
+return {
+  "fragment": {
+    "argumentDefinitions": (v0/*: any*/),
+    "kind": "Fragment",
+    "metadata": null,
+    "name": "ResetPasswordMutation",
+    "selections": (v1/*: any*/),
+    "type": "Mutation",
+    "abstractKey": null
+  },
+  "kind": "Request",
+  "operation": {
+    "argumentDefinitions": (v0/*: any*/),
+    "kind": "Operation",
+    "name": "ResetPasswordMutation",
+    "selections": (v1/*: any*/)
+  },
+  "params": {
+    "cacheID": "6d42d490d9599db0e28417e4900dbc61",
+    "id": null,
+    "metadata": {},
+    "name": "ResetPasswordMutation",
+    "operationKind": "mutation",
+    "text": "mutation ResetPasswordMutation(\n  $token: String!\n  $newPassword: String!\n) {\n  resetPassword(token: $token, newPassword: $newPassword)\n}\n"
+  }
+};
+})();
+(node as any).hash = 'e3c73994ae224910582376f8d135ec97';
+export default node;
@@ -0,0 +1,79 @@
+// deno-lint-ignore-file 
+/* tslint:disable */
+/* eslint-disable */
+// @ts-nocheck
+
+import { ConcreteRequest } from "relay-runtime";
+export type VerifyEmailMutationVariables = {
+    token: string;
+};
+export type VerifyEmailMutationResponse = {
+    readonly verifyEmail: boolean;
+};
+export type VerifyEmailMutation = {
+    readonly response: VerifyEmailMutationResponse;
+    readonly variables: VerifyEmailMutationVariables;
+};
+
+
+
+/*
+mutation VerifyEmailMutation(
+  $token: String!
+) {
+  verifyEmail(token: $token)
+}
+*/
+
+const node: ConcreteRequest = (function(){
+var v0 = [
+  {
+    "defaultValue": null,
+    "kind": "LocalArgument",
+    "name": "token"
+  }
+],
+v1 = [
+  {
+    "alias": null,
+    "args": [
+      {
+        "kind": "Variable",
+        "name": "token",
+        "variableName": "token"
+      }
+    ],
+    "kind": "ScalarField",
+    "name": "verifyEmail",
+    "storageKey": null
+  }
+];
+return {
+  "fragment": {
+    "argumentDefinitions": (v0/*: any*/),
+    "kind": "Fragment",
+    "metadata": null,
+    "name": "VerifyEmailMutation",
+    "selections": (v1/*: any*/),
+    "type": "Mutation",
+    "abstractKey": null
+  },
+  "kind": "Request",
+  "operation": {
+    "argumentDefinitions": (v0/*: any*/),
+    "kind": "Operation",
+    "name": "VerifyEmailMutation",
+    "selections": (v1/*: any*/)
+  },
+  "params": {
+    "cacheID": "e405cc0c6ac972ea5c36e3d492142d5c",
+    "id": null,
+    "metadata": {},
+    "name": "VerifyEmailMutation",
+    "operationKind": "mutation",
+    "text": "mutation VerifyEmailMutation(\n  $token: String!\n) {\n  verifyEmail(token: $token)\n}\n"
+  }
+};
+})();
+(node as any).hash = 'fbbfe9995ad772f3a09e5c120b9b359b';
+export default node;
@@ -0,0 +1,19 @@
+
+import * as React from "react";
+import * as ReactHelmet from "react-helmet";
+
+const Lazy = React.lazy(() => import("./Lazy/ResetPassword.tsx"));
+
+export function Helmet()
+{
+    const element: React.ReactElement =
+        <ReactHelmet.Helmet>
+            <title>turtle | reset password</title>
+        </ReactHelmet.Helmet>;
+    return element;
+}
+
+export function Page()
+{
+    return <Lazy />;
+}
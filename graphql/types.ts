@@ -25,7 +25,7 @@ export type Error = {
 
 export type Mutation = {
   __typename?: 'Mutation';
-  createUser: User;
+  createUser: Scalars['Boolean'];
   loginUser: Scalars['String'];
   logoutUser: Scalars['Boolean'];
   revokeUser: Scalars['Boolean'];
@@ -209,7 +209,7 @@ export type ErrorResolvers<ContextType = any, ParentType extends ResolversParent
 };
 
 export type MutationResolvers<ContextType = any, ParentType extends ResolversParentTypes['Mutation'] = ResolversParentTypes['Mutation']> = {
-  createUser?: Resolver<ResolversTypes['User'], ParentType, ContextType, RequireFields<MutationCreateUserArgs, 'email' | 'password'>>;
+  createUser?: Resolver<ResolversTypes['Boolean'], ParentType, ContextType, RequireFields<MutationCreateUserArgs, 'email' | 'password'>>;
   loginUser?: Resolver<ResolversTypes['String'], ParentType, ContextType, RequireFields<MutationLoginUserArgs, 'email' | 'password'>>;
   logoutUser?: Resolver<ResolversTypes['Boolean'], ParentType, ContextType>;
   revokeUser?: Resolver<ResolversTypes['Boolean'], ParentType, ContextType, RequireFields<MutationRevokeUserArgs, 'id'>>;
@@ -1,143 +1,184 @@
-use crate::core::{context, error, message};
+use crate::core::{config, context, error, keys, message, middleware, redis};
 use crate::custom::jwt;
 
-use redis::AsyncCommands;
-use rsa::{pkcs8::ToPrivateKey, pkcs8::ToPublicKey};
+/* Registered claims of RFC 7519, set by the token type rather than the payload. */
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Registered {
+    pub iss: String,
+    pub aud: String,
+    /* Signed tokens always expire, API keys only when created to. */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<usize>,
+    pub nbf: usize,
+    pub iat: usize,
+}
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Claims {
     pub sub: String,
-    pub exp: usize,
+    #[serde(flatten)]
+    pub registered: Registered,
     pub ajd: jwt::AdditionalData, /* Additional JSON Data claim. */
     pub jti: Option<String>,      /* JWT receipt. */
 }
 
 impl Claims {
-    fn from(payload: &jwt::Payload, exp: usize) -> Self {
-        jwt::convert(payload, exp)
+    fn from(payload: &jwt::Payload, registered: Registered) -> Self {
+        jwt::convert(payload, registered)
     }
 }
 
 pub trait Token {
-    fn new(lifetime: usize, path: String) -> Result<Self, error::Error>
+    /* The "typ" header, so one kind of token is never taken for the other. */
+    const TYP: &'static str;
+    fn new(keyring: keys::Keyring, config: &config::AuthConfig, path: String) -> Self
     where
         Self: Sized;
-    fn expiry(lifetime: usize) -> Result<usize, error::Error> {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)?
-            .as_secs() as usize;
-        Ok(now + lifetime)
-    }
-    fn private(&self) -> String;
-    fn public(&self) -> String;
+    fn keyring(&self) -> &keys::Keyring;
+    fn lifetime(&self) -> usize;
+    fn audience(&self) -> &str;
+    /* Seconds of clock skew tolerated on "exp" and "nbf". */
+    fn leeway(&self) -> u64;
+    fn registered(&self, issuer: &str) -> Result<Registered, error::Error> {
+        let now = crate::core::util::now()? as usize;
+        let registered = Registered {
+            iss: issuer.to_string(),
+            aud: self.audience().to_string(),
+            exp: Some(now + self.lifetime()),
+            nbf: now,
+            iat: now,
+        };
+        Ok(registered)
+    }
     fn create(
         &self,
         payload: jwt::Payload,
+        issuer: &str,
         message: &mut message::Message,
     ) -> Result<String, error::Error>;
-    fn verify(&self, token: String) -> Result<Claims, error::Error> {
-        let algorithm = jsonwebtoken::Algorithm::RS256;
-        let validation = jsonwebtoken::Validation::new(algorithm);
-        let public_key = self.public();
-        let key = jsonwebtoken::DecodingKey::from_rsa_pem(public_key.as_bytes())?;
-        let claims = jsonwebtoken::decode::<Claims>(token.as_str(), &key, &validation)?.claims;
-        Ok(claims)
+    fn sign(&self, claims: &Claims) -> Result<String, error::Error> {
+        let keypair = self.keyring().signing()?;
+        let mut header = jsonwebtoken::Header::new(keypair.algorithm);
+        header.typ = Some(Self::TYP.to_string());
+        header.kid = Some(keypair.kid.clone());
+        Ok(jsonwebtoken::encode(
+            &header,
+            claims,
+            keypair.encoding_key(),
+        )?)
     }
-}
-#[derive(Clone, Debug)]
-pub struct Keypair {
-    private: String,
-    public: String,
-}
-impl Keypair {
-    fn new() -> Result<Self, error::Error> {
-        let private_rsa = rsa::RsaPrivateKey::new(&mut rand::rngs::OsRng, 2048)?;
-        let private = private_rsa.to_pkcs8_pem()?.to_string();
-
-        let public_rsa = rsa::RsaPublicKey::from(&private_rsa);
-        let public = public_rsa.to_public_key_pem()?;
-
-        let instance = Self { private, public };
-        Ok(instance)
+    fn verify(&self, token: String, issuer: &str) -> Result<Claims, error::Error> {
+        let header = jsonwebtoken::decode_header(token.as_str())?;
+        if header.typ.as_deref() != Some(Self::TYP) {
+            return Err(error::Error::new_str("Wrong token type"));
+        }
+        let mut result = Err(error::Error::new_str("No matching verification key"));
+        for keypair in self.keyring().verifying(header.kid.as_deref())? {
+            /* Each key only accepts its own algorithm, whatever the header says. */
+            let mut validation = jsonwebtoken::Validation::new(keypair.algorithm);
+            validation.leeway = self.leeway();
+            validation.validate_nbf = true;
+            validation.set_issuer(&[issuer]);
+            validation.set_audience(&[self.audience()]);
+            match jsonwebtoken::decode::<Claims>(
+                token.as_str(),
+                keypair.decoding_key(),
+                &validation,
+            ) {
+                Ok(data) => return Ok(data.claims),
+                Err(error) => result = Err(error.into()),
+            }
+        }
+        result
     }
 }
 #[derive(Clone, Debug)]
 pub struct AccessToken {
-    keypair: Keypair,
+    keyring: keys::Keyring,
     lifetime: usize,
+    audience: String,
+    leeway: u64,
 }
 impl Token for AccessToken {
-    fn new(lifetime: usize, _path: String) -> Result<Self, error::Error> {
-        let instance = Self {
-            keypair: Keypair::new()?,
-            lifetime,
-        };
-        Ok(instance)
+    const TYP: &'static str = "at+jwt";
+    fn new(keyring: keys::Keyring, config: &config::AuthConfig, _path: String) -> Self {
+        Self {
+            keyring,
+            lifetime: config.access_lifetime,
+            audience: config.access_audience.clone(),
+            leeway: config.leeway,
+        }
+    }
+    fn keyring(&self) -> &keys::Keyring {
+        &self.keyring
+    }
+    fn lifetime(&self) -> usize {
+        self.lifetime
     }
-    fn private(&self) -> String {
-        self.keypair.private.clone()
+    fn audience(&self) -> &str {
+        self.audience.as_str()
     }
-    fn public(&self) -> String {
-        self.keypair.public.clone()
+    fn leeway(&self) -> u64 {
+        self.leeway
     }
     fn create(
         &self,
         payload: jwt::Payload,
+        issuer: &str,
         _message: &mut message::Message,
     ) -> Result<String, error::Error> {
-        let algorithm = jsonwebtoken::Algorithm::RS256;
-        let header = jsonwebtoken::Header::new(algorithm);
-        let private_key = self.private();
-        let claims = Claims::from(&payload, AccessToken::expiry(self.lifetime)?);
-        let key = jsonwebtoken::EncodingKey::from_rsa_pem(private_key.as_bytes())?;
-        Ok(jsonwebtoken::encode(&header, &claims, &key)?)
+        let claims = Claims::from(&payload, self.registered(issuer)?);
+        self.sign(&claims)
     }
 }
 #[derive(Clone, Debug)]
 pub struct RefreshToken {
-    keypair: Keypair,
+    keyring: keys::Keyring,
     lifetime: usize,
+    audience: String,
+    leeway: u64,
     path: String,
 }
 impl Token for RefreshToken {
-    fn new(lifetime: usize, path: String) -> Result<Self, error::Error> {
-        let instance = Self {
-            keypair: Keypair::new()?,
-            lifetime,
+    const TYP: &'static str = "rt+jwt";
+    fn new(keyring: keys::Keyring, config: &config::AuthConfig, path: String) -> Self {
+        Self {
+            keyring,
+            lifetime: config.refresh_lifetime,
+            audience: config.refresh_audience.clone(),
+            leeway: config.leeway,
             path,
-        };
-        Ok(instance)
+        }
+    }
+    fn keyring(&self) -> &keys::Keyring {
+        &self.keyring
+    }
+    fn lifetime(&self) -> usize {
+        self.lifetime
     }
-    fn private(&self) -> String {
-        self.keypair.private.clone()
+    fn audience(&self) -> &str {
+        self.audience.as_str()
     }
-    fn public(&self) -> String {
-        self.keypair.public.clone()
+    fn leeway(&self) -> u64 {
+        self.leeway
     }
     fn create(
         &self,
         payload: jwt::Payload,
+        issuer: &str,
         message: &mut message::Message,
     ) -> Result<String, error::Error> {
-        let algorithm = jsonwebtoken::Algorithm::RS256;
-        let header = jsonwebtoken::Header::new(algorithm);
-        let private_key = self.private();
-        let claims = Claims::from(&payload, AccessToken::expiry(self.lifetime)?);
-
-        match jsonwebtoken::EncodingKey::from_rsa_pem(private_key.as_bytes()) {
-            Ok(key) => {
-                let token = jsonwebtoken::encode(&header, &claims, &key)?;
-                let cookie = cookie::Cookie::build("refresh", token.clone())
-                    .http_only(true)
-                    .path(self.path.clone())
-                    .secure(true)
-                    .same_site(cookie::SameSite::Strict)
-                    .finish();
-                message.cookies.add(cookie);
-                Ok(token)
-            }
-            Err(error) => Err(error.into()),
-        }
+        let claims = Claims::from(&payload, self.registered(issuer)?);
+        let token = self.sign(&claims)?;
+        let cookie = cookie::Cookie::build("refresh", token.clone())
+            .http_only(true)
+            .path(self.path.clone())
+            .secure(true)
+            .same_site(cookie::SameSite::Strict)
+            .finish();
+        message.cookies.add(cookie);
+        middleware::csrf::issue(message);
+        Ok(token)
     }
 }
 impl RefreshToken {
@@ -149,74 +190,1262 @@ impl RefreshToken {
             .same_site(cookie::SameSite::Strict)
             .finish();
         message.cookies.remove(cookie);
+        middleware::csrf::reset(message);
     }
 }
 #[derive(Clone, Debug)]
 pub struct AuthContext {
     pub access: AccessToken,
     pub refresh: RefreshToken,
-    pub salt: scrypt::password_hash::SaltString,
 }
 impl AuthContext {
-    pub fn new() -> Result<Self, error::Error> {
+    pub async fn new(
+        config: &config::AuthConfig,
+        redis: &redis::RedisContext,
+    ) -> Result<Self, error::Error> {
         crate::console_log!("Creating authentication context...");
 
-        let access_lifetime = 60 * 15;
-        let access = AccessToken::new(access_lifetime, "/jwt/access".to_string())?;
-        let refresh_lifetime = 60 * 60 * 24 * 7;
-        let refresh = RefreshToken::new(refresh_lifetime, "/jwt/refresh".to_string())?;
+        password::validate(&config.password)?;
+        if config.issuer.is_none() {
+            crate::console_warn!(
+                "auth.issuer is not set, emailed links are disabled and CSRF checks trust the Host header"
+            );
+        }
+        if config.access_audience == config.refresh_audience {
+            return Err(error::Error::new_str(
+                "Access and refresh tokens need distinct audiences",
+            ));
+        }
+        let store = keys::Store::new(&config.keys, redis);
+        let access_keyring =
+            keys::Keyring::load("access", config.keys.access_algorithm, &store).await?;
+        let refresh_keyring =
+            keys::Keyring::load("refresh", config.keys.refresh_algorithm, &store).await?;
+        let keyrings = vec![access_keyring.clone(), refresh_keyring.clone()];
+        keys::watch(keyrings, store, config.keys.reload_interval);
 
-        let salt = scrypt::password_hash::SaltString::generate(
-            &mut scrypt::password_hash::rand_core::OsRng,
-        );
+        let access = AccessToken::new(access_keyring, config, "/jwt/access".to_string());
+        let refresh = RefreshToken::new(refresh_keyring, config, "/jwt/refresh".to_string());
 
-        let instance = Self {
-            access,
-            refresh,
-            salt,
-        };
+        let instance = Self { access, refresh };
         Ok(instance)
     }
+    /* Rotates both keyrings, keeping retired keys until their tokens expire. */
+    pub async fn rotate(
+        config: &config::AuthConfig,
+        redis: &redis::RedisContext,
+    ) -> Result<(), error::Error> {
+        let store = keys::Store::new(&config.keys, redis);
+        /* Give every replica one reload before the new key starts signing. */
+        let delay = config.keys.reload_interval * 2;
+        let rotations = [
+            (
+                "access",
+                config.keys.access_algorithm,
+                config.access_lifetime,
+            ),
+            (
+                "refresh",
+                config.keys.refresh_algorithm,
+                config.refresh_lifetime,
+            ),
+        ];
+        for (name, algorithm, lifetime) in rotations.iter() {
+            let kid = keys::rotate(&store, name, *algorithm, delay, *lifetime as u64).await?;
+            crate::console_log!("Rotated {} key to {}", name, kid);
+        }
+        Ok(())
+    }
+}
+
+/* Passwords are stored as PHC strings carrying their own salt and cost, so */
+/* hashes made under older settings keep verifying until they are rehashed. */
+pub mod password {
+    use super::*;
+    use scrypt::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+    use std::convert::TryFrom;
+
+    fn scrypt_params(config: &config::PasswordConfig) -> Result<scrypt::Params, error::Error> {
+        let scrypt = &config.scrypt;
+        Ok(scrypt::Params::new(scrypt.log_n, scrypt.r, scrypt.p)?)
+    }
+    fn argon2_params(config: &config::PasswordConfig) -> Result<argon2::Params, error::Error> {
+        let argon2 = &config.argon2;
+        Ok(argon2::Params::new(
+            argon2.memory,
+            argon2.iterations,
+            argon2.parallelism,
+            None,
+        )?)
+    }
+    pub fn validate(config: &config::PasswordConfig) -> Result<(), error::Error> {
+        scrypt_params(config)?;
+        argon2_params(config)?;
+        Ok(())
+    }
+    /* Hashes a password with a fresh random salt and the configured cost. */
+    pub fn hash(config: &config::PasswordConfig, password: &str) -> Result<String, error::Error> {
+        let salt = SaltString::generate(&mut scrypt::password_hash::rand_core::OsRng);
+        let hash = match config.algorithm {
+            config::PasswordAlgorithm::Scrypt => scrypt::Scrypt
+                .hash_password_customized(
+                    password.as_bytes(),
+                    None,
+                    None,
+                    scrypt_params(config)?,
+                    salt.as_salt(),
+                )?
+                .to_string(),
+            config::PasswordAlgorithm::Argon2id => argon2::Argon2::new(
+                argon2::Algorithm::Argon2id,
+                argon2::Version::V0x13,
+                argon2_params(config)?,
+            )
+            .hash_password(password.as_bytes(), &salt)?
+            .to_string(),
+        };
+        Ok(hash)
+    }
+    pub fn verify(password: &str, hash: &str) -> Result<bool, error::Error> {
+        let hash = PasswordHash::new(hash)?;
+        let result = match hash.algorithm.as_str() {
+            "scrypt" => scrypt::Scrypt.verify_password(password.as_bytes(), &hash),
+            "argon2id" | "argon2i" | "argon2d" => {
+                argon2::Argon2::default().verify_password(password.as_bytes(), &hash)
+            }
+            algorithm => {
+                let message = format!("Unsupported password hash algorithm {}", algorithm);
+                return Err(error::Error::new_string(message));
+            }
+        };
+        match result {
+            Ok(()) => Ok(true),
+            Err(scrypt::password_hash::Error::Password) => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    }
+    /* Whether a stored hash differs from the configured algorithm or cost. */
+    pub fn outdated(config: &config::PasswordConfig, hash: &str) -> Result<bool, error::Error> {
+        let hash = PasswordHash::new(hash)?;
+        match config.algorithm {
+            config::PasswordAlgorithm::Scrypt => {
+                if hash.algorithm.as_str() != "scrypt" {
+                    return Ok(true);
+                }
+                let current = scrypt::Params::try_from(&hash)?;
+                let wanted = scrypt_params(config)?;
+                Ok((current.log_n(), current.r(), current.p())
+                    != (wanted.log_n(), wanted.r(), wanted.p()))
+            }
+            config::PasswordAlgorithm::Argon2id => {
+                if hash.algorithm != argon2::Algorithm::Argon2id.ident()
+                    || hash.version != Some(argon2::Version::V0x13.into())
+                {
+                    return Ok(true);
+                }
+                let current = argon2::Params::try_from(&hash)?;
+                let wanted = argon2_params(config)?;
+                Ok((current.m_cost(), current.t_cost(), current.p_cost())
+                    != (wanted.m_cost(), wanted.t_cost(), wanted.p_cost()))
+            }
+        }
+    }
+}
+
+/* A refresh token family starts at login and moves to a new token on every */
+/* refresh. Tokens carry "<family>.<token>" as their jti and only the latest */
+/* token of a family is valid, so replaying an older one revokes the family. */
+/* Each family is also a session, which users can list and end one by one. */
+pub mod revocation {
+    use super::*;
+    use ::redis::AsyncCommands;
+
+    pub enum Refresh {
+        Rotated(String),
+        Reused,
+        Revoked,
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct Session {
+        pub id: String,
+        pub user_agent: String,
+        pub address: String,
+        pub created: u64,
+        pub last_used: u64,
+    }
+
+    const ROTATE_SCRIPT: &str = r"
+        local family = redis.call('HMGET', KEYS[1], 'sub', 'current')
+        if not family[1] or family[1] ~= ARGV[1] then
+            return 'revoked'
+        end
+        if family[2] ~= ARGV[2] then
+            redis.call('DEL', KEYS[1])
+            redis.call('SREM', KEYS[2], ARGV[5])
+            return 'reused'
+        end
+        redis.call('HSET', KEYS[1], 'current', ARGV[3], 'last_used', ARGV[6], 'address', ARGV[7])
+        redis.call('EXPIRE', KEYS[1], ARGV[4])
+        redis.call('EXPIRE', KEYS[2], ARGV[4])
+        return 'rotated'
+    ";
+
+    fn family_key(family: &str) -> String {
+        format!("refresh:families:{}", family)
+    }
+    fn user_key(sub: &str) -> String {
+        format!("refresh:users:{}", sub)
+    }
+    pub fn parse(jti: &str) -> Option<(&str, &str)> {
+        jti.split_once('.')
+    }
+    pub fn user_agent(message: &message::Message) -> String {
+        message
+            .request
+            .headers()
+            .get(hyper::header::USER_AGENT)
+            .and_then(|user_agent| user_agent.to_str().ok())
+            .unwrap_or_default()
+            .chars()
+            .take(256)
+            .collect()
+    }
+
+    /* Starts a family for a new login and returns the jti of its first token. */
+    pub async fn start(
+        context: &context::Context,
+        sub: &str,
+        user_agent: &str,
+        address: std::net::IpAddr,
+    ) -> Result<String, error::Error> {
+        let family = crate::core::util::uuid();
+        let token = crate::core::util::uuid();
+        let now = crate::core::util::now()?.to_string();
+        let lifetime = context.config.auth.refresh_lifetime;
+        let mut redis_main = context.redis.main().await?;
+        ::redis::pipe()
+            .atomic()
+            .hset_multiple(
+                family_key(family.as_str()),
+                &[
+                    ("sub", sub),
+                    ("current", token.as_str()),
+                    ("user_agent", user_agent),
+                    ("address", address.to_string().as_str()),
+                    ("created", now.as_str()),
+                    ("last_used", now.as_str()),
+                ],
+            )
+            .ignore()
+            .expire(family_key(family.as_str()), lifetime)
+            .ignore()
+            .sadd(user_key(sub), family.as_str())
+            .ignore()
+            .expire(user_key(sub), lifetime)
+            .ignore()
+            .query_async::<_, ()>(&mut redis_main)
+            .await?;
+        Ok(format!("{}.{}", family, token))
+    }
+    /* Moves the family of a verified refresh token on to a new token. */
+    pub async fn rotate(
+        context: &context::Context,
+        claims: &Claims,
+        address: std::net::IpAddr,
+    ) -> Result<Refresh, error::Error> {
+        let (family, token) = match claims.jti.as_deref().and_then(parse) {
+            Some(jti) => jti,
+            None => return Ok(Refresh::Revoked),
+        };
+        let next = crate::core::util::uuid();
+        let mut redis_main = context.redis.main().await?;
+        let result = ::redis::Script::new(ROTATE_SCRIPT)
+            .key(family_key(family))
+            .key(user_key(claims.sub.as_str()))
+            .arg(claims.sub.as_str())
+            .arg(token)
+            .arg(next.as_str())
+            .arg(context.config.auth.refresh_lifetime)
+            .arg(family)
+            .arg(crate::core::util::now()?)
+            .arg(address.to_string())
+            .invoke_async::<_, String>(&mut redis_main)
+            .await?;
+        match result.as_str() {
+            "rotated" => Ok(Refresh::Rotated(format!("{}.{}", family, next))),
+            "reused" => Ok(Refresh::Reused),
+            _ => Ok(Refresh::Revoked),
+        }
+    }
+    /* Live sessions of a user, oldest first. Families that expired are */
+    /* dropped from the user's set on the way. */
+    pub async fn sessions(
+        context: &context::Context,
+        sub: &str,
+    ) -> Result<Vec<Session>, error::Error> {
+        let mut redis_main = context.redis.main().await?;
+        let families = redis_main.smembers::<_, Vec<String>>(user_key(sub)).await?;
+        let mut sessions = vec![];
+        for family in families {
+            let fields = redis_main
+                .hgetall::<_, std::collections::HashMap<String, String>>(family_key(
+                    family.as_str(),
+                ))
+                .await?;
+            if fields.get("sub").map(String::as_str) != Some(sub) {
+                redis_main.srem::<_, _, ()>(user_key(sub), family).await?;
+                continue;
+            }
+            let field = |name: &str| fields.get(name).cloned().unwrap_or_default();
+            let timestamp = |name: &str| field(name).parse::<u64>().unwrap_or_default();
+            sessions.push(Session {
+                user_agent: field("user_agent"),
+                address: field("address"),
+                created: timestamp("created"),
+                last_used: timestamp("last_used"),
+                id: family,
+            });
+        }
+        sessions.sort_by_key(|session| session.created);
+        Ok(sessions)
+    }
+    /* Ends one session of a user, returning whether it was live. */
+    pub async fn revoke_session(
+        context: &context::Context,
+        sub: &str,
+        id: &str,
+    ) -> Result<bool, error::Error> {
+        let mut redis_main = context.redis.main().await?;
+        /* Only members of the user's own set, ids of others do nothing. */
+        if !redis_main
+            .sismember::<_, _, bool>(user_key(sub), id)
+            .await?
+        {
+            return Ok(false);
+        }
+        let (removed, _) = ::redis::pipe()
+            .atomic()
+            .del(family_key(id))
+            .srem(user_key(sub), id)
+            .query_async::<_, (u32, u32)>(&mut redis_main)
+            .await?;
+        Ok(removed > 0)
+    }
+    /* The session a request belongs to, from its refresh cookie. */
+    pub fn current(message: &message::Message, context: &context::Context) -> Option<String> {
+        let refresh = message.cookies.get("refresh")?;
+        let issuer = util::issuer(message, context);
+        let claims = context
+            .auth
+            .refresh
+            .verify(refresh.value().to_string(), issuer.as_str())
+            .ok()?;
+        let (family, _token) = parse(claims.jti.as_deref()?)?;
+        Some(family.to_string())
+    }
+    /* Revokes every family of a user, signing them out everywhere. */
+    pub async fn revoke(context: &context::Context, sub: &str) -> Result<(), error::Error> {
+        let mut redis_main = context.redis.main().await?;
+        let families = redis_main.smembers::<_, Vec<String>>(user_key(sub)).await?;
+        let mut keys = families
+            .iter()
+            .map(|family| family_key(family.as_str()))
+            .collect::<Vec<_>>();
+        keys.push(user_key(sub));
+        redis_main.del::<_, ()>(keys).await?;
+        Ok(())
+    }
+    /* Revokes the family of a refresh token, returning whether it was live. */
+    pub async fn revoke_token(
+        context: &context::Context,
+        refresh: String,
+        issuer: &str,
+    ) -> Result<bool, error::Error> {
+        let claims = match context.auth.refresh.verify(refresh, issuer) {
+            Ok(claims) => claims,
+            Err(_error) => return Ok(false),
+        };
+        let family = match claims.jti.as_deref().and_then(parse) {
+            Some((family, _token)) => family,
+            None => return Ok(false),
+        };
+        let mut redis_main = context.redis.main().await?;
+        let (removed, _) = ::redis::pipe()
+            .atomic()
+            .del(family_key(family))
+            .srem(user_key(claims.sub.as_str()), family)
+            .query_async::<_, (u32, u32)>(&mut redis_main)
+            .await?;
+        Ok(removed > 0)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn parses_family_and_token() {
+            assert_eq!(parse("family.token"), Some(("family", "token")));
+            assert_eq!(parse("family"), None);
+        }
+
+        async fn rotate(
+            redis_main: &mut ::redis::aio::MultiplexedConnection,
+            family: &str,
+            token: &str,
+            next: &str,
+        ) -> String {
+            ::redis::Script::new(ROTATE_SCRIPT)
+                .key(family_key(family))
+                .key(user_key("user:test"))
+                .arg("user:test")
+                .arg(token)
+                .arg(next)
+                .arg(60)
+                .arg(family)
+                .arg(0)
+                .arg("127.0.0.1")
+                .invoke_async(redis_main)
+                .await
+                .unwrap()
+        }
+        #[tokio::test]
+        #[ignore = "needs Redis with scripting, set REDIS_URL"]
+        async fn detects_reuse() {
+            let mut redis_main = crate::core::redis::RedisContext::test().await;
+            let family = crate::core::util::uuid();
+            redis_main
+                .hset_multiple::<_, _, _, ()>(
+                    family_key(family.as_str()),
+                    &[("sub", "user:test"), ("current", "first")],
+                )
+                .await
+                .unwrap();
+            let rotated = rotate(&mut redis_main, family.as_str(), "first", "second").await;
+            assert_eq!(rotated, "rotated");
+            /* The first token again, the family is revoked for good. */
+            let reused = rotate(&mut redis_main, family.as_str(), "first", "third").await;
+            assert_eq!(reused, "reused");
+            let revoked = rotate(&mut redis_main, family.as_str(), "second", "third").await;
+            assert_eq!(revoked, "revoked");
+        }
+    }
+}
+
+/* Single-use tokens for links sent by email. Only a hash of each token is */
+/* stored, keyed by its purpose, and redeeming a token deletes it. */
+pub mod ticket {
+    use super::*;
+    use ::redis::AsyncCommands;
+    use sha2::Digest;
+
+    fn key(purpose: &str, token: &str) -> String {
+        let digest = sha2::Sha256::digest(token.as_bytes());
+        format!("tickets:{}:{:x}", purpose, digest)
+    }
+    pub async fn issue(
+        context: &context::Context,
+        purpose: &str,
+        sub: &str,
+        lifetime: usize,
+    ) -> Result<String, error::Error> {
+        let mut bytes = [0u8; 32];
+        rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut bytes);
+        let token = base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+        let mut redis_main = context.redis.main().await?;
+        redis_main
+            .set_ex::<_, _, ()>(key(purpose, token.as_str()), sub, lifetime)
+            .await?;
+        Ok(token)
+    }
+    /* Returns the subject the token was issued to, at most once. */
+    pub async fn redeem(
+        context: &context::Context,
+        purpose: &str,
+        token: &str,
+    ) -> Result<Option<String>, error::Error> {
+        let mut redis_main = context.redis.main().await?;
+        let sub = ::redis::cmd("GETDEL")
+            .arg(key(purpose, token))
+            .query_async::<_, Option<String>>(&mut redis_main)
+            .await?;
+        Ok(sub)
+    }
+}
+
+/* Failed logins are counted per account and per client address. Each */
+/* failure past a threshold pushes the next allowed attempt further out, */
+/* and enough of them lock the account or address for a while. */
+pub mod lockout {
+    use super::*;
+    use ::redis::AsyncCommands;
+
+    const FAILURE_SCRIPT: &str = r"
+        local now = tonumber(ARGV[1])
+        local window = tonumber(ARGV[2])
+        local delay_after = tonumber(ARGV[3])
+        local delay = tonumber(ARGV[4])
+        local max_delay = tonumber(ARGV[5])
+        local lock_after = tonumber(ARGV[6])
+        local lock_duration = tonumber(ARGV[7])
+        local failures = redis.call('HINCRBY', KEYS[1], 'failures', 1)
+        local retry_at = now
+        if failures >= lock_after then
+            retry_at = now + lock_duration
+        elseif failures >= delay_after then
+            retry_at = now + math.min(max_delay, delay * 2 ^ (failures - delay_after))
+        end
+        redis.call('HSET', KEYS[1], 'retry_at', retry_at)
+        redis.call('EXPIRE', KEYS[1], math.max(window, retry_at - now))
+        return {failures, retry_at}
+    ";
+
+    fn account_key(email: &str) -> String {
+        format!("lockout:accounts:{}", email.trim().to_lowercase())
+    }
+    fn address_key(address: std::net::IpAddr) -> String {
+        format!("lockout:addresses:{}", address)
+    }
+
+    /* Seconds until the next attempt is allowed, None if it is allowed now. */
+    pub async fn check(
+        context: &context::Context,
+        email: &str,
+        address: std::net::IpAddr,
+    ) -> Result<Option<u64>, error::Error> {
+        if !context.config.auth.lockout.enabled {
+            return Ok(None);
+        }
+        let mut redis_main = context.redis.main().await?;
+        let mut retry_at = 0;
+        for key in &[account_key(email), address_key(address)] {
+            let value = redis_main
+                .hget::<_, _, Option<u64>>(key.as_str(), "retry_at")
+                .await?;
+            retry_at = retry_at.max(value.unwrap_or_default());
+        }
+        let now = crate::core::util::now()?;
+        match retry_at > now {
+            true => Ok(Some(retry_at - now)),
+            false => Ok(None),
+        }
+    }
+    pub async fn fail(
+        context: &context::Context,
+        email: &str,
+        address: std::net::IpAddr,
+    ) -> Result<(), error::Error> {
+        let config = &context.config.auth.lockout;
+        if !config.enabled {
+            return Ok(());
+        }
+        let now = crate::core::util::now()?;
+        let mut redis_main = context.redis.main().await?;
+        /* Addresses are only locked, many users may share one. */
+        let thresholds = [
+            (account_key(email), config.delay_after, config.lock_after),
+            (
+                address_key(address),
+                config.address_lock_after,
+                config.address_lock_after,
+            ),
+        ];
+        for (key, delay_after, lock_after) in thresholds.iter() {
+            let (failures, retry_at) = ::redis::Script::new(FAILURE_SCRIPT)
+                .key(key.as_str())
+                .arg(now)
+                .arg(config.window)
+                .arg(*delay_after)
+                .arg(config.delay)
+                .arg(config.max_delay)
+                .arg(*lock_after)
+                .arg(config.lock_duration)
+                .invoke_async::<_, (u32, u64)>(&mut redis_main)
+                .await?;
+            if failures == *lock_after {
+                crate::console_error!(
+                    "Security: {} locked until {} after {} failed logins",
+                    key,
+                    retry_at,
+                    failures
+                );
+            }
+        }
+        Ok(())
+    }
+    /* A successful login clears the account, not the address. */
+    pub async fn succeed(context: &context::Context, email: &str) -> Result<(), error::Error> {
+        unlock(context, email).await
+    }
+    pub async fn unlock(context: &context::Context, email: &str) -> Result<(), error::Error> {
+        let mut redis_main = context.redis.main().await?;
+        redis_main.del::<_, ()>(account_key(email)).await?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn normalizes_account_keys() {
+            assert_eq!(
+                account_key(" User@Example.com "),
+                account_key("user@example.com")
+            );
+        }
+        #[tokio::test]
+        #[ignore = "needs Redis with scripting, set REDIS_URL"]
+        async fn delays_then_locks() {
+            let config = config::LockoutConfig::default();
+            let mut redis_main = crate::core::redis::RedisContext::test().await;
+            let key = account_key(format!("{}@example.com", crate::core::util::uuid()).as_str());
+            let mut delays = vec![];
+            for _ in 0..config.lock_after {
+                let (_failures, retry_at) = ::redis::Script::new(FAILURE_SCRIPT)
+                    .key(key.as_str())
+                    .arg(1000)
+                    .arg(config.window)
+                    .arg(config.delay_after)
+                    .arg(config.delay)
+                    .arg(config.max_delay)
+                    .arg(config.lock_after)
+                    .arg(config.lock_duration)
+                    .invoke_async::<_, (u32, u64)>(&mut redis_main)
+                    .await
+                    .unwrap();
+                delays.push(retry_at - 1000);
+            }
+            redis_main.del::<_, ()>(key).await.unwrap();
+            /* Free attempts, then doubling delays up to the maximum, then */
+            /* the lock. */
+            assert_eq!(delays, vec![0, 0, 1, 2, 4, 8, 16, 32, 60, 900]);
+        }
+    }
+}
+
+/* Long-lived credentials for scripts, "tk_<id>_<secret>" where the id */
+/* finds the key and only a hash of the whole key is stored. Keys act for */
+/* their user, limited to the permissions in their scopes. */
+pub mod api_key {
+    use super::*;
+    use ::redis::AsyncCommands;
+    use sha2::Digest;
+
+    const PREFIX: &str = "tk_";
+
+    #[derive(Clone, Debug)]
+    pub struct ApiKey {
+        pub id: String,
+        pub name: String,
+        pub scopes: Vec<jwt::Permission>,
+        pub created: u64,
+        pub expires: Option<u64>,
+        pub last_used: Option<u64>,
+    }
+
+    fn key(id: &str) -> String {
+        format!("api_keys:{}", id)
+    }
+    fn user_key(sub: &str) -> String {
+        format!("api_keys:users:{}", sub)
+    }
+    fn digest(api_key: &str) -> String {
+        format!("{:x}", sha2::Sha256::digest(api_key.as_bytes()))
+    }
+    /* Returns the id of a well-formed key. */
+    pub fn parse(api_key: &str) -> Option<&str> {
+        let (id, _secret) = api_key.strip_prefix(PREFIX)?.split_once('_')?;
+        match id.len() == 16 && id.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            true => Some(id),
+            false => None,
+        }
+    }
+    fn from_fields(
+        id: String,
+        fields: &std::collections::HashMap<String, String>,
+    ) -> Result<ApiKey, error::Error> {
+        let timestamp = |name: &str| fields.get(name).and_then(|value| value.parse().ok());
+        let scopes = match fields.get("scopes") {
+            Some(scopes) => serde_json::from_str(scopes)?,
+            None => vec![],
+        };
+        let api_key = ApiKey {
+            id,
+            name: fields.get("name").cloned().unwrap_or_default(),
+            scopes,
+            created: timestamp("created").unwrap_or_default(),
+            expires: timestamp("expires"),
+            last_used: timestamp("last_used"),
+        };
+        Ok(api_key)
+    }
+
+    /* Returns the key, which is shown once, and its description. */
+    pub async fn create(
+        context: &context::Context,
+        sub: &str,
+        name: &str,
+        scopes: &[jwt::Permission],
+        expires: Option<u64>,
+    ) -> Result<(String, ApiKey), error::Error> {
+        let config = &context.config.auth.api_keys;
+        if !config.enabled {
+            return Err(error::Error::new_str("API keys are disabled"));
+        }
+        if list(context, sub).await?.len() >= config.max_per_user {
+            let message = format!("No more than {} API keys per user", config.max_per_user);
+            return Err(error::Error::new_string(message));
+        }
+        let now = crate::core::util::now()?;
+        if matches!(expires, Some(expires) if expires <= now) {
+            return Err(error::Error::new_str("API key expiry is in the past"));
+        }
+        let id = crate::core::util::uuid().replace('-', "")[..16].to_string();
+        let mut bytes = [0u8; 32];
+        rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut bytes);
+        let secret = base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+        let value = format!("{}{}_{}", PREFIX, id, secret);
+
+        let mut fields = vec![
+            ("sub", sub.to_string()),
+            ("name", name.to_string()),
+            ("hash", digest(value.as_str())),
+            ("scopes", serde_json::to_string(scopes)?),
+            ("created", now.to_string()),
+        ];
+        if let Some(expires) = expires {
+            fields.push(("expires", expires.to_string()));
+        }
+        let mut redis_main = context.redis.main().await?;
+        let mut pipe = ::redis::pipe();
+        pipe.atomic()
+            .hset_multiple(key(id.as_str()), &fields)
+            .ignore()
+            .sadd(user_key(sub), id.as_str())
+            .ignore();
+        if let Some(expires) = expires {
+            pipe.expire_at(key(id.as_str()), expires as usize).ignore();
+        }
+        pipe.query_async::<_, ()>(&mut redis_main).await?;
+
+        let api_key = ApiKey {
+            id,
+            name: name.to_string(),
+            scopes: scopes.to_vec(),
+            created: now,
+            expires,
+            last_used: None,
+        };
+        Ok((value, api_key))
+    }
+    /* Keys of a user, oldest first. Expired keys are dropped on the way. */
+    pub async fn list(context: &context::Context, sub: &str) -> Result<Vec<ApiKey>, error::Error> {
+        let mut redis_main = context.redis.main().await?;
+        let ids = redis_main.smembers::<_, Vec<String>>(user_key(sub)).await?;
+        let mut api_keys = vec![];
+        for id in ids {
+            let fields = redis_main
+                .hgetall::<_, std::collections::HashMap<String, String>>(key(id.as_str()))
+                .await?;
+            if fields.get("sub").map(String::as_str) != Some(sub) {
+                redis_main.srem::<_, _, ()>(user_key(sub), id).await?;
+                continue;
+            }
+            api_keys.push(from_fields(id, &fields)?);
+        }
+        api_keys.sort_by_key(|api_key| api_key.created);
+        Ok(api_keys)
+    }
+    /* Returns whether the user had a key with this id. */
+    pub async fn revoke(
+        context: &context::Context,
+        sub: &str,
+        id: &str,
+    ) -> Result<bool, error::Error> {
+        let mut redis_main = context.redis.main().await?;
+        if !redis_main
+            .sismember::<_, _, bool>(user_key(sub), id)
+            .await?
+        {
+            return Ok(false);
+        }
+        ::redis::pipe()
+            .atomic()
+            .del(key(id))
+            .ignore()
+            .srem(user_key(sub), id)
+            .ignore()
+            .query_async::<_, ()>(&mut redis_main)
+            .await?;
+        Ok(true)
+    }
+    /* Revokes every key of a user, e.g. when the account is deleted or its */
+    /* password is reset. */
+    pub async fn revoke_all(context: &context::Context, sub: &str) -> Result<(), error::Error> {
+        let mut redis_main = context.redis.main().await?;
+        let ids = redis_main.smembers::<_, Vec<String>>(user_key(sub)).await?;
+        let mut keys = ids.iter().map(|id| key(id)).collect::<Vec<_>>();
+        keys.push(user_key(sub));
+        redis_main.del::<_, ()>(keys).await?;
+        Ok(())
+    }
+    /* Claims for a valid key, built from the current state of its user. */
+    pub async fn verify(
+        context: &context::Context,
+        api_key: &str,
+        issuer: &str,
+    ) -> Result<Claims, error::Error> {
+        let invalid = || error::Error::new_str("Invalid API key");
+        if !context.config.auth.api_keys.enabled {
+            return Err(error::Error::new_str("API keys are disabled"));
+        }
+        let id = parse(api_key).ok_or_else(invalid)?;
+        let mut redis_main = context.redis.main().await?;
+        let fields = redis_main
+            .hgetall::<_, std::collections::HashMap<String, String>>(key(id))
+            .await?;
+        /* The secret has 256 random bits, so comparing digests leaks nothing useful. */
+        if fields.get("hash") != Some(&digest(api_key)) {
+            return Err(invalid());
+        }
+        let sub = fields.get("sub").cloned().ok_or_else(invalid)?;
+        let now = crate::core::util::now()?;
+        let description = from_fields(id.to_string(), &fields)?;
+        if matches!(description.expires, Some(expires) if expires <= now) {
+            return Err(error::Error::new_str("API key has expired"));
+        }
+        redis_main
+            .hset::<_, _, _, ()>(key(id), "last_used", now)
+            .await?;
+
+        let mut redis_json = context.redis.json().await?;
+        let user = redis_json.get(sub.clone(), None, None).await?;
+        let user = serde_json::from_str::<jwt::Payload>(user.as_str())?;
+        let roles = jwt::roles(
+            &context.config.auth,
+            &user.email,
+            user.email_verified,
+            &user.roles,
+        );
+        let payload = jwt::Payload {
+            jti: None,
+            roles,
+            ..user
+        };
+        /* Shaped like an access token, so guards cannot tell them apart. */
+        let registered = Registered {
+            iss: issuer.to_string(),
+            aud: context.auth.access.audience().to_string(),
+            exp: description.expires.map(|expires| expires as usize),
+            nbf: description.created as usize,
+            iat: description.created as usize,
+        };
+        let mut claims = Claims::from(&payload, registered);
+        claims.ajd.scopes = Some(description.scopes);
+        Ok(claims)
+    }
+}
+
+/* Declarative authorization rules for resolvers, for example */
+/* `Subject(id).or(HasPermission(Permission::ManageUsers))`. */
+pub mod guard {
+    use super::*;
+
+    pub trait Guard {
+        fn allows(&self, claims: &Claims) -> bool;
+        fn or<Other>(self, other: Other) -> Or<Self, Other>
+        where
+            Self: Sized,
+            Other: Guard,
+        {
+            Or(self, other)
+        }
+        fn and<Other>(self, other: Other) -> And<Self, Other>
+        where
+            Self: Sized,
+            Other: Guard,
+        {
+            And(self, other)
+        }
+    }
+
+    /* Any valid access token. */
+    pub struct Authenticated;
+    impl Guard for Authenticated {
+        fn allows(&self, _claims: &Claims) -> bool {
+            true
+        }
+    }
+    /* An access token from a login. API keys act for their user but */
+    /* cannot manage the account itself. */
+    pub struct Interactive;
+    impl Guard for Interactive {
+        fn allows(&self, claims: &Claims) -> bool {
+            claims.ajd.scopes.is_none()
+        }
+    }
+    /* The token belongs to the user with this id. */
+    pub struct Subject<'a>(pub &'a str);
+    impl<'a> Guard for Subject<'a> {
+        fn allows(&self, claims: &Claims) -> bool {
+            claims.sub == self.0
+        }
+    }
+    /* The token belongs to the user with this email. */
+    pub struct Email<'a>(pub &'a str);
+    impl<'a> Guard for Email<'a> {
+        fn allows(&self, claims: &Claims) -> bool {
+            claims.ajd.email.eq_ignore_ascii_case(self.0)
+        }
+    }
+    pub struct HasPermission(pub jwt::Permission);
+    impl Guard for HasPermission {
+        fn allows(&self, claims: &Claims) -> bool {
+            claims.ajd.permits(self.0)
+        }
+    }
+    pub struct Or<A, B>(A, B);
+    impl<A: Guard, B: Guard> Guard for Or<A, B> {
+        fn allows(&self, claims: &Claims) -> bool {
+            self.0.allows(claims) || self.1.allows(claims)
+        }
+    }
+
+    pub struct And<A, B>(A, B);
+    impl<A: Guard, B: Guard> Guard for And<A, B> {
+        fn allows(&self, claims: &Claims) -> bool {
+            self.0.allows(claims) && self.1.allows(claims)
+        }
+    }
+
+    /* Authenticates the credentials, then applies the guard to their claims. */
+    pub async fn authorize<G: Guard>(
+        credentials: util::Credentials,
+        context: &context::Context,
+        guard: G,
+    ) -> Result<Claims, error::Error> {
+        let claims = util::authenticate(credentials, context).await?;
+        match guard.allows(&claims) {
+            true => Ok(claims),
+            false => Err(error::Error::new_str("Not authorized")),
+        }
+    }
 }
 
 pub mod util {
     use super::*;
-    pub fn authenticate(
+    /* What authenticating a request needs, taken out of the message so */
+    /* that it can be held across await points. */
+    #[derive(Clone, Debug)]
+    pub struct Credentials {
+        pub token: String,
+        pub issuer: String,
+    }
+    /* The bearer token of the "Authorization" header. */
+    pub fn credentials(
         message: &message::Message,
         context: &context::Context,
-    ) -> Result<Claims, error::Error> {
+    ) -> Result<Credentials, error::Error> {
         if let Some(authorization) = message.request.headers().get("authorization") {
             let token = authorization
                 .to_str()?
                 .to_string()
                 .replace("Bearer ", "")
                 .replace("bearer ", "");
-            context.auth.access.verify(token)
+            let issuer = issuer(message, context);
+            Ok(Credentials { token, issuer })
         } else {
             Err(error::Error::new_str(
                 "\"Authorization\" header not present",
             ))
         }
     }
-    /* @todo: use rate limiting */
-    #[allow(dead_code)]
+    /* Accepts an access token or an API key. */
+    pub async fn authenticate(
+        credentials: Credentials,
+        context: &context::Context,
+    ) -> Result<Claims, error::Error> {
+        let Credentials { token, issuer } = credentials;
+        match api_key::parse(token.as_str()) {
+            Some(_) => api_key::verify(context, token.as_str(), issuer.as_str()).await,
+            None => context.auth.access.verify(token, issuer.as_str()),
+        }
+    }
+    /* Token bucket, refilled continuously and updated atomically. */
+    const RATE_LIMIT_SCRIPT: &str = r"
+        local capacity = tonumber(ARGV[1])
+        local period = tonumber(ARGV[2])
+        local now = tonumber(ARGV[3])
+        local rate = capacity / period
+        local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'timestamp')
+        local tokens = tonumber(bucket[1]) or capacity
+        local timestamp = tonumber(bucket[2]) or now
+        tokens = math.min(capacity, tokens + math.max(0, now - timestamp) * rate)
+        local allowed = 0
+        if tokens >= 1 then
+            tokens = tokens - 1
+            allowed = 1
+        end
+        redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'timestamp', now)
+        redis.call('PEXPIRE', KEYS[1], period)
+        local retry = 0
+        if allowed == 0 then
+            retry = math.ceil((1 - tokens) / rate)
+        end
+        local reset = math.ceil((capacity - tokens) / rate)
+        return { allowed, math.floor(tokens), retry, reset }
+    ";
+    #[derive(Clone, Copy, Debug)]
+    pub struct RateLimit {
+        pub allowed: bool,
+        pub limit: u32,
+        pub remaining: u64,
+        /* Milliseconds until the bucket is full again. */
+        pub reset: u64,
+        /* Milliseconds until the next request is allowed. */
+        pub retry_after: u64,
+    }
+    impl RateLimit {
+        pub fn headers(&self, response: &mut hyper::Response<hyper::Body>) {
+            fn seconds(milliseconds: u64) -> hyper::header::HeaderValue {
+                hyper::header::HeaderValue::from(milliseconds.div_ceil(1000))
+            }
+            let headers = response.headers_mut();
+            headers.insert("ratelimit-limit", self.limit.into());
+            headers.insert("ratelimit-remaining", self.remaining.into());
+            headers.insert("ratelimit-reset", seconds(self.reset));
+            if !self.allowed {
+                headers.insert(hyper::header::RETRY_AFTER, seconds(self.retry_after));
+            }
+        }
+    }
+    /* The configured issuer only. Links mailed to users must never follow */
+    /* the Host header, which whoever sends the request controls. */
+    pub fn public_url(context: &context::Context) -> Result<String, error::Error> {
+        match &context.config.auth.issuer {
+            Some(issuer) => Ok(issuer.trim_end_matches('/').to_string()),
+            None => Err(error::Error::new_str(
+                "auth.issuer must be set to send emails with links",
+            )),
+        }
+    }
+    /* The configured issuer, or the URL this request was sent to. */
+    pub fn issuer(message: &message::Message, context: &context::Context) -> String {
+        if let Some(issuer) = &context.config.auth.issuer {
+            return issuer.trim_end_matches('/').to_string();
+        }
+        let scheme = match context.config.tls {
+            Some(_) => "https",
+            None => "http",
+        };
+        let host = match message.request.uri().authority() {
+            Some(authority) => authority.to_string(),
+            None => match message.request.headers().get(hyper::header::HOST) {
+                Some(value) => value.to_str().unwrap_or_default().to_string(),
+                None => format!(
+                    "{}:{}",
+                    context.config.server.hostname, context.config.server.port
+                ),
+            },
+        };
+        format!("{}://{}", scheme, host)
+    }
+    /* Authenticated users are limited by subject, everyone else by IP. */
+    pub async fn rate_limit_identity(
+        message: &message::Message,
+        context: &context::Context,
+        policy: &str,
+    ) -> String {
+        let per_user = match context.config.rate_limit.policies.get(policy) {
+            Some(policy) => policy.per_user,
+            None => false,
+        };
+        if per_user {
+            if let Ok(credentials) = credentials(message, context) {
+                if let Ok(claims) = authenticate(credentials, context).await {
+                    return format!("user:{}", claims.sub);
+                }
+            }
+        }
+        format!("ip:{}", message.address.ip())
+    }
+    /* Returns None when limiting is disabled, the policy is unknown or */
+    /* Redis is unavailable, so requests are never rejected by accident. */
     pub async fn rate_limit(
-        message: &mut message::Message,
         context: &context::Context,
-        limit: usize,
-        expiry: usize,
-    ) -> Result<(), error::Error> {
-        let key = format!("rate-limit:{}", message.address.ip().to_string());
-        let mut redis_main = context.redis.main().await?;
-        let count = redis_main
-            .incr::<'_, _, _, usize>(key.clone(), 1_usize)
-            .await?;
+        policy: &str,
+        identity: &str,
+    ) -> Option<RateLimit> {
+        let config = &context.config.rate_limit;
+        let rate_limit_policy = match config.policies.get(policy) {
+            Some(rate_limit_policy) if config.enabled => rate_limit_policy,
+            _ => return None,
+        };
+        async fn invoke(
+            context: &context::Context,
+            key: String,
+            policy: &config::RateLimitPolicy,
+        ) -> Result<Vec<u64>, error::Error> {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_millis() as u64;
+            let mut redis_main = context.redis.main().await?;
+            let result = ::redis::Script::new(RATE_LIMIT_SCRIPT)
+                .key(key)
+                .arg(policy.capacity)
+                .arg(policy.period * 1000)
+                .arg(now)
+                .invoke_async(&mut redis_main)
+                .await?;
+            Ok(result)
+        }
+        let key = format!("rate-limit:{}:{}", policy, identity);
+        match invoke(context, key, rate_limit_policy).await {
+            Ok(result) if result.len() == 4 => Some(RateLimit {
+                allowed: result[0] == 1,
+                limit: rate_limit_policy.capacity,
+                remaining: result[1],
+                reset: result[3],
+                retry_after: result[2],
+            }),
+            Ok(_result) => None,
+            Err(error) => {
+                crate::console_warn!("Rate limiting failed: {}", error);
+                None
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
 
-        if count > limit {
-            *message.response.status_mut() = hyper::StatusCode::TOO_MANY_REQUESTS;
-        } else if 1 >= count {
-            redis_main.expire(key, expiry).await?;
+        fn headers(rate_limit: RateLimit) -> hyper::HeaderMap {
+            let mut response = hyper::Response::new(hyper::Body::empty());
+            rate_limit.headers(&mut response);
+            response.headers().clone()
         }
-        Ok(())
+        #[test]
+        fn rounds_headers_up_to_seconds() {
+            let allowed = headers(RateLimit {
+                allowed: true,
+                limit: 5,
+                remaining: 4,
+                reset: 1001,
+                retry_after: 0,
+            });
+            assert_eq!(allowed["ratelimit-limit"], "5");
+            assert_eq!(allowed["ratelimit-remaining"], "4");
+            assert_eq!(allowed["ratelimit-reset"], "2");
+            assert!(!allowed.contains_key(hyper::header::RETRY_AFTER));
+
+            let denied = headers(RateLimit {
+                allowed: false,
+                limit: 5,
+                remaining: 0,
+                reset: 60000,
+                retry_after: 1,
+            });
+            assert_eq!(denied["ratelimit-remaining"], "0");
+            assert_eq!(denied[hyper::header::RETRY_AFTER], "1");
+        }
+        async fn take(
+            redis_main: &mut ::redis::aio::MultiplexedConnection,
+            key: &str,
+            now: u64,
+        ) -> Vec<u64> {
+            ::redis::Script::new(RATE_LIMIT_SCRIPT)
+                .key(key)
+                .arg(2)
+                .arg(1000)
+                .arg(now)
+                .invoke_async(redis_main)
+                .await
+                .unwrap()
+        }
+        #[tokio::test]
+        #[ignore = "needs Redis with scripting, set REDIS_URL"]
+        async fn refills_bucket() {
+            let mut redis_main = crate::core::redis::RedisContext::test().await;
+            let key = &format!("rate-limit:test:{}", crate::core::util::uuid());
+            /* Allowed, remaining, retry after and reset, in milliseconds. */
+            assert_eq!(take(&mut redis_main, key, 0).await, vec![1, 1, 0, 500]);
+            assert_eq!(take(&mut redis_main, key, 0).await, vec![1, 0, 0, 1000]);
+            assert_eq!(take(&mut redis_main, key, 0).await, vec![0, 0, 500, 1000]);
+            /* Half the period refills one of the two tokens. */
+            assert_eq!(take(&mut redis_main, key, 500).await, vec![1, 0, 0, 1000]);
+            assert_eq!(take(&mut redis_main, key, 5000).await, vec![1, 1, 0, 500]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ISSUER: &str = "https://turtle.example";
+
+    fn claims(token: &impl Token) -> Claims {
+        let payload = jwt::Payload {
+            id: juniper::ID::new("user"),
+            jti: None,
+            email: "user@turtle.example".to_string(),
+            email_verified: true,
+            roles: vec![],
+        };
+        Claims::from(&payload, token.registered(ISSUER).unwrap())
+    }
+
+    /* Both token types share one key. */
+    fn tokens(config: &config::AuthConfig) -> (AccessToken, RefreshToken) {
+        let keyring = keys::Keyring::test(config::SigningAlgorithm::HS256);
+        (
+            AccessToken::new(keyring.clone(), config, "/".to_string()),
+            RefreshToken::new(keyring, config, "/".to_string()),
+        )
+    }
+
+    #[test]
+    fn rejects_the_other_token_type() {
+        /* Same audience too, leaving the "typ" header as the only check. */
+        let config = config::AuthConfig::default();
+        let (access, refresh) = tokens(&config::AuthConfig {
+            refresh_audience: config.access_audience.clone(),
+            ..config
+        });
+        let access_token = access.sign(&claims(&access)).unwrap();
+        let refresh_token = refresh.sign(&claims(&refresh)).unwrap();
+
+        assert!(access.verify(access_token.clone(), ISSUER).is_ok());
+        assert!(refresh.verify(refresh_token.clone(), ISSUER).is_ok());
+        assert!(refresh.verify(access_token, ISSUER).is_err());
+        assert!(access.verify(refresh_token, ISSUER).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_audience() {
+        let config = config::AuthConfig::default();
+        let (access, _refresh) = tokens(&config);
+        let other = AccessToken::new(
+            access.keyring().clone(),
+            &config::AuthConfig {
+                access_audience: "other:access".to_string(),
+                ..config
+            },
+            "/".to_string(),
+        );
+        let token = access.sign(&claims(&access)).unwrap();
+
+        assert!(other.verify(token, ISSUER).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_issuer() {
+        let (access, _refresh) = tokens(&config::AuthConfig::default());
+        let token = access.sign(&claims(&access)).unwrap();
+
+        assert!(access.verify(token, "https://other.example").is_err());
     }
 }
@@ -0,0 +1,762 @@
+use crate::core::error;
+
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "turtle")]
+pub struct Arguments {
+    /// Path to the TOML configuration file
+    #[structopt(short, long, parse(from_os_str))]
+    pub config: Option<std::path::PathBuf>,
+    /// Address to bind the server to
+    #[structopt(long)]
+    pub hostname: Option<std::net::IpAddr>,
+    /// Port to bind the server to
+    #[structopt(short, long)]
+    pub port: Option<u16>,
+    /// URL of the Redis instance
+    #[structopt(long)]
+    pub redis_url: Option<String>,
+    /// Path to the PEM certificate chain, enables HTTPS
+    #[structopt(long, parse(from_os_str))]
+    pub certificate: Option<std::path::PathBuf>,
+    /// Path to the PEM private key, enables HTTPS
+    #[structopt(long, parse(from_os_str))]
+    pub key: Option<std::path::PathBuf>,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Clone, Copy, Debug, StructOpt)]
+pub enum Command {
+    /// Generate new JWT signing keys, which start signing after every
+    /// running server has reloaded them
+    RotateKeys,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub hostname: std::net::IpAddr,
+    pub port: u16,
+}
+impl Default for ServerConfig {
+    #[cfg(debug_assertions)]
+    fn default() -> Self {
+        Self {
+            hostname: [127, 0, 0, 1].into(),
+            port: 3080,
+        }
+    }
+    #[cfg(not(debug_assertions))]
+    fn default() -> Self {
+        Self {
+            hostname: [0, 0, 0, 0].into(),
+            port: 3080,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub certificate: std::path::PathBuf,
+    pub key: std::path::PathBuf,
+    /* Plain HTTP port that redirects to HTTPS, if any. */
+    pub redirect_port: Option<u16>,
+    /* Seconds between certificate change checks, 0 disables reloading. */
+    pub reload_interval: u64,
+    /* Seconds a client has to complete the TLS handshake. */
+    pub handshake_timeout: u64,
+}
+impl Default for TlsConfig {
+    fn default() -> Self {
+        let root = std::path::Path::new(".").join("cert").join("localhost");
+        Self {
+            certificate: root.join("fullchain.pem"),
+            key: root.join("privkey.pem"),
+            redirect_port: None,
+            reload_interval: 60,
+            handshake_timeout: 10,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct Http2Config {
+    /* Offer HTTP/2 through ALPN on TLS connections. */
+    pub enabled: bool,
+    /* Accept HTTP/2 with prior knowledge on plain HTTP connections. */
+    pub h2c: bool,
+    pub max_concurrent_streams: Option<u32>,
+    pub initial_stream_window_size: Option<u32>,
+    pub initial_connection_window_size: Option<u32>,
+    pub adaptive_window: bool,
+}
+impl Default for Http2Config {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            h2c: cfg!(debug_assertions),
+            max_concurrent_streams: Some(200),
+            initial_stream_window_size: None,
+            initial_connection_window_size: None,
+            adaptive_window: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyStore {
+    Redis,
+    Files,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct KeysConfig {
+    pub store: KeyStore,
+    /* Root of the "access" and "refresh" PEM directories for the files store. */
+    pub directory: std::path::PathBuf,
+    /* Seconds between key reloads, 0 disables reloading. */
+    pub reload_interval: u64,
+    /* Algorithms of newly generated keys, existing keys keep their own. */
+    pub access_algorithm: SigningAlgorithm,
+    pub refresh_algorithm: SigningAlgorithm,
+}
+impl Default for KeysConfig {
+    fn default() -> Self {
+        Self {
+            store: KeyStore::Redis,
+            directory: std::path::Path::new(".").join("keys"),
+            reload_interval: 60,
+            access_algorithm: SigningAlgorithm::RS256,
+            refresh_algorithm: SigningAlgorithm::RS256,
+        }
+    }
+}
+
+/* JSON Web Algorithms names. HS256 keys are never published, so they only */
+/* suit tokens this server verifies itself, like refresh tokens. */
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+pub enum SigningAlgorithm {
+    RS256,
+    ES256,
+    EdDSA,
+    HS256,
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordAlgorithm {
+    Scrypt,
+    Argon2id,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct ScryptConfig {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+impl Default for ScryptConfig {
+    fn default() -> Self {
+        Self {
+            log_n: 15,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct Argon2Config {
+    /* Memory cost in KiB. */
+    pub memory: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+impl Default for Argon2Config {
+    fn default() -> Self {
+        Self {
+            memory: 19456,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct PasswordConfig {
+    /* New and rehashed passwords use this, existing hashes still verify. */
+    pub algorithm: PasswordAlgorithm,
+    pub scrypt: ScryptConfig,
+    pub argon2: Argon2Config,
+}
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: PasswordAlgorithm::Scrypt,
+            scrypt: ScryptConfig::default(),
+            argon2: Argon2Config::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verification {
+    /* Unverified accounts log in like any other. */
+    Optional,
+    /* Unverified accounts only get an access token, without a refresh cookie. */
+    Limited,
+    /* Unverified accounts cannot log in. */
+    Required,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct TotpConfig {
+    /* Account issuer shown by authenticator apps. */
+    pub issuer: String,
+    /* Time steps of clock drift accepted either side of now. */
+    pub skew: u64,
+    /* Seconds to enter a code after the password at login. */
+    pub challenge_lifetime: usize,
+    /* Seconds to confirm a new secret before enrollment must restart. */
+    pub enrollment_lifetime: usize,
+}
+impl Default for TotpConfig {
+    fn default() -> Self {
+        Self {
+            issuer: "Turtle".to_string(),
+            skew: 1,
+            challenge_lifetime: 60 * 5,
+            enrollment_lifetime: 60 * 10,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct LockoutConfig {
+    pub enabled: bool,
+    /* Seconds failed logins are remembered after the last one. */
+    pub window: u64,
+    /* Failures on one account before attempts are delayed. */
+    pub delay_after: u32,
+    /* Seconds of the first delay, doubled with every further failure. */
+    pub delay: u64,
+    pub max_delay: u64,
+    /* Failures on one account before it is locked. */
+    pub lock_after: u32,
+    /* Failures from one address before it is locked. */
+    pub address_lock_after: u32,
+    /* Seconds a lock lasts, unless an administrator lifts it. */
+    pub lock_duration: u64,
+}
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window: 60 * 15,
+            delay_after: 3,
+            delay: 1,
+            max_delay: 60,
+            lock_after: 10,
+            address_lock_after: 50,
+            lock_duration: 60 * 15,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct ApiKeysConfig {
+    pub enabled: bool,
+    pub max_per_user: usize,
+}
+impl Default for ApiKeysConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_per_user: 20,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct OidcProviderConfig {
+    /* Provider URL, described by <issuer>/.well-known/openid-configuration. */
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    #[serde(default = "OidcProviderConfig::scopes")]
+    pub scopes: Vec<String>,
+}
+impl OidcProviderConfig {
+    fn scopes() -> Vec<String> {
+        vec!["openid".to_string(), "email".to_string()]
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct OidcConfig {
+    /* Seconds to finish signing in at the provider. */
+    pub state_lifetime: usize,
+    /* Page the browser returns to, with the outcome in the URL fragment. */
+    pub redirect: String,
+    pub providers: std::collections::HashMap<String, OidcProviderConfig>,
+}
+impl Default for OidcConfig {
+    fn default() -> Self {
+        Self {
+            state_lifetime: 60 * 10,
+            redirect: "/".to_string(),
+            providers: std::collections::HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /* Public base URL of this server, derived from the Host header if unset. */
+    pub issuer: Option<String>,
+    pub access_lifetime: usize,
+    pub refresh_lifetime: usize,
+    /* "aud" claims, distinct so a refresh token is never accepted as a bearer. */
+    pub access_audience: String,
+    pub refresh_audience: String,
+    /* Seconds of clock skew tolerated when checking "exp" and "nbf". */
+    pub leeway: u64,
+    /* Seconds a password reset link stays valid. */
+    pub reset_lifetime: usize,
+    /* Seconds an email verification link stays valid. */
+    pub verify_lifetime: usize,
+    pub verification: Verification,
+    pub keys: KeysConfig,
+    pub password: PasswordConfig,
+    pub totp: TotpConfig,
+    pub lockout: LockoutConfig,
+    pub oidc: OidcConfig,
+    pub api_keys: ApiKeysConfig,
+    /* Emails of accounts that get the admin role once verified, whatever */
+    /* their stored roles. */
+    pub admins: Vec<String>,
+}
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            issuer: None,
+            access_lifetime: 60 * 15,
+            refresh_lifetime: 60 * 60 * 24 * 7,
+            access_audience: "turtle:access".to_string(),
+            refresh_audience: "turtle:refresh".to_string(),
+            leeway: 30,
+            reset_lifetime: 60 * 60,
+            verify_lifetime: 60 * 60 * 24,
+            verification: Verification::Optional,
+            keys: KeysConfig::default(),
+            password: PasswordConfig::default(),
+            totp: TotpConfig::default(),
+            lockout: LockoutConfig::default(),
+            oidc: OidcConfig::default(),
+            api_keys: ApiKeysConfig::default(),
+            admins: vec![],
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct RateLimitPolicy {
+    /* Requests allowed in a burst, refilled evenly over the period. */
+    pub capacity: u32,
+    /* Seconds to refill an empty bucket. */
+    pub period: u64,
+    /* Key authenticated requests by user rather than by IP address. */
+    #[serde(default)]
+    pub per_user: bool,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /* Policies from the file are merged over the built-in ones. */
+    #[serde(deserialize_with = "RateLimitConfig::policies")]
+    pub policies: std::collections::HashMap<String, RateLimitPolicy>,
+}
+impl RateLimitConfig {
+    fn policies<'de, D>(
+        deserializer: D,
+    ) -> Result<std::collections::HashMap<String, RateLimitPolicy>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::Deserialize;
+        let mut policies = Self::default().policies;
+        let overrides =
+            std::collections::HashMap::<String, RateLimitPolicy>::deserialize(deserializer)?;
+        policies.extend(overrides);
+        Ok(policies)
+    }
+}
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let policy = |capacity, period, per_user| RateLimitPolicy {
+            capacity,
+            period,
+            per_user,
+        };
+        let mut policies = std::collections::HashMap::new();
+        policies.insert("graphql".to_string(), policy(120, 60, true));
+        policies.insert("refresh".to_string(), policy(10, 60, false));
+        policies.insert("login".to_string(), policy(5, 60, false));
+        policies.insert("reset".to_string(), policy(3, 60 * 15, false));
+        policies.insert("verify".to_string(), policy(3, 60 * 15, false));
+        Self {
+            enabled: true,
+            policies,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct CsrfConfig {
+    pub enabled: bool,
+    /* Origins other than the issuer allowed to send cookie-authenticated */
+    /* requests, such as "https://app.example.com". */
+    pub trusted_origins: Vec<String>,
+    /* Without auth.issuer the expected origin is the Host header, with the */
+    /* scheme of X-Forwarded-Proto when set. Only for proxies that always */
+    /* overwrite that header. */
+    pub trust_forwarded_proto: bool,
+}
+impl Default for CsrfConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            trusted_origins: vec![],
+            trust_forwarded_proto: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    /* Exact origins, or patterns where "*" stands for one or more host or */
+    /* port characters, to allow every subdomain for example. Empty */
+    /* disables CORS. */
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    /* Response headers scripts of other origins may read. */
+    pub exposed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    /* Seconds browsers may cache a preflight response. */
+    pub max_age: u64,
+}
+impl Default for CorsConfig {
+    fn default() -> Self {
+        let strings = |values: &[&str]| values.iter().map(|value| value.to_string()).collect();
+        Self {
+            allowed_origins: vec![],
+            allowed_methods: strings(&["GET", "POST"]),
+            allowed_headers: strings(&["authorization", "content-type", "x-csrf-token"]),
+            exposed_headers: strings(&[
+                "ratelimit-limit",
+                "ratelimit-remaining",
+                "ratelimit-reset",
+                "retry-after",
+                "x-csrf-token",
+            ]),
+            allow_credentials: true,
+            max_age: 60 * 10,
+        }
+    }
+}
+
+/* Unset fields fall back to the top-level security headers settings. */
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct SecurityHeadersOverride {
+    pub content_security_policy: Option<String>,
+    pub frame_ancestors: Option<String>,
+    pub referrer_policy: Option<String>,
+    pub permissions_policy: Option<String>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct SecurityHeadersConfig {
+    pub enabled: bool,
+    /* Strict-Transport-Security max-age, only sent over TLS, 0 disables it. */
+    pub hsts_max_age: u64,
+    pub hsts_include_subdomains: bool,
+    pub hsts_preload: bool,
+    /* "{nonce}" is replaced by a per-request nonce, which is also added to */
+    /* the script and style tags of index.html. */
+    pub content_security_policy: String,
+    /* Appended to the policy, and mirrored in X-Frame-Options when it is */
+    /* 'none' or 'self'. */
+    pub frame_ancestors: String,
+    pub referrer_policy: String,
+    pub permissions_policy: String,
+    /* Keyed by path prefix matched on whole segments, the longest matching */
+    /* prefix applies. Routes from the file are merged over the built-in */
+    /* ones. */
+    #[serde(deserialize_with = "SecurityHeadersConfig::routes")]
+    pub routes: std::collections::HashMap<String, SecurityHeadersOverride>,
+}
+impl SecurityHeadersConfig {
+    fn routes<'de, D>(
+        deserializer: D,
+    ) -> Result<std::collections::HashMap<String, SecurityHeadersOverride>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::Deserialize;
+        let mut routes = Self::default().routes;
+        let overrides = std::collections::HashMap::<String, SecurityHeadersOverride>::deserialize(
+            deserializer,
+        )?;
+        routes.extend(overrides);
+        Ok(routes)
+    }
+}
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        /* GraphiQL loads its scripts and styles from CDNs and inlines both. */
+        let graphiql = SecurityHeadersOverride {
+            content_security_policy: Some(
+                [
+                    "default-src 'self'",
+                    "script-src 'self' 'unsafe-inline' https://cdn.jsdelivr.net \
+                     https://cdnjs.cloudflare.com https://unpkg.com",
+                    "style-src 'self' 'unsafe-inline' https://cdn.jsdelivr.net",
+                    "img-src 'self' data:",
+                    "font-src 'self' data:",
+                    "connect-src 'self'",
+                    "object-src 'none'",
+                    "base-uri 'self'",
+                ]
+                .join("; "),
+            ),
+            ..SecurityHeadersOverride::default()
+        };
+        let mut routes = std::collections::HashMap::new();
+        routes.insert("/graphql".to_string(), graphiql);
+        Self {
+            enabled: true,
+            hsts_max_age: 60 * 60 * 24 * 365 * 2,
+            hsts_include_subdomains: true,
+            hsts_preload: false,
+            content_security_policy: [
+                "default-src 'self'",
+                "script-src 'self' 'nonce-{nonce}'",
+                "style-src 'self' 'nonce-{nonce}'",
+                "img-src 'self' data:",
+                "connect-src 'self'",
+                "object-src 'none'",
+                "base-uri 'self'",
+                "form-action 'self'",
+            ]
+            .join("; "),
+            frame_ancestors: "'none'".to_string(),
+            referrer_policy: "strict-origin-when-cross-origin".to_string(),
+            permissions_policy: "camera=(), microphone=(), geolocation=(), payment=()".to_string(),
+            routes,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    Outbox,
+    Smtp,
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    None,
+    /* Upgrade a plain connection, usually on port 587. */
+    StartTls,
+    /* Implicit TLS, usually on port 465. */
+    Tls,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /* Name announced in EHLO. */
+    pub hello: String,
+    /* Seconds before a delivery is abandoned. */
+    pub timeout: u64,
+}
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 587,
+            security: SmtpSecurity::StartTls,
+            username: None,
+            password: None,
+            hello: "localhost".to_string(),
+            timeout: 30,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct MailConfig {
+    pub transport: MailTransport,
+    pub from: String,
+    /* Directory the outbox transport writes .eml files to. */
+    pub outbox: std::path::PathBuf,
+    pub smtp: SmtpConfig,
+}
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            transport: MailTransport::Outbox,
+            from: "Turtle <turtle@localhost>".to_string(),
+            outbox: std::path::Path::new(".").join("outbox"),
+            smtp: SmtpConfig::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct RedisConfig {
+    pub url: String,
+}
+impl Default for RedisConfig {
+    fn default() -> Self {
+        Self {
+            url: "redis://0.0.0.0:6379".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub tls: Option<TlsConfig>,
+    pub http2: Http2Config,
+    pub auth: AuthConfig,
+    pub rate_limit: RateLimitConfig,
+    pub csrf: CsrfConfig,
+    pub cors: CorsConfig,
+    pub security_headers: SecurityHeadersConfig,
+    pub mail: MailConfig,
+    pub redis: RedisConfig,
+}
+impl Config {
+    pub fn load(arguments: Arguments) -> Result<Self, error::Error> {
+        crate::console_log!("Loading configuration...");
+
+        /* Precedence is file, then environment, then command-line flags. */
+        let mut config = Self::file(&arguments)?;
+        config.environment()?;
+        config.arguments(arguments);
+        Ok(config)
+    }
+    fn file(arguments: &Arguments) -> Result<Self, error::Error> {
+        let path = match (&arguments.config, std::env::var_os("TURTLE_CONFIG")) {
+            (Some(path), _) => path.clone(),
+            (None, Some(path)) => path.into(),
+            (None, None) => {
+                /* The default file is optional, explicit paths are not. */
+                let path = std::path::Path::new(".").join("turtle.toml");
+                if !path.is_file() {
+                    return Ok(Self::default());
+                }
+                path
+            }
+        };
+        let contents = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(contents.as_str())?)
+    }
+    fn environment(&mut self) -> Result<(), error::Error> {
+        fn var<T>(key: &str) -> Result<Option<T>, error::Error>
+        where
+            T: std::str::FromStr,
+            T::Err: std::fmt::Display,
+        {
+            match std::env::var(key) {
+                Ok(value) => match value.parse() {
+                    Ok(value) => Ok(Some(value)),
+                    Err(error) => {
+                        let message = format!("Invalid value for {}: {}", key, error);
+                        Err(error::Error::new_string(message))
+                    }
+                },
+                Err(_error) => Ok(None),
+            }
+        }
+        if let Some(hostname) = var("TURTLE_HOSTNAME")? {
+            self.server.hostname = hostname;
+        }
+        if let Some(port) = var("TURTLE_PORT")? {
+            self.server.port = port;
+        }
+        if let Some(certificate) = var("TURTLE_TLS_CERTIFICATE")? {
+            self.tls.get_or_insert_with(TlsConfig::default).certificate = certificate;
+        }
+        if let Some(key) = var("TURTLE_TLS_KEY")? {
+            self.tls.get_or_insert_with(TlsConfig::default).key = key;
+        }
+        if let Some(issuer) = var("TURTLE_ISSUER")? {
+            self.auth.issuer = Some(issuer);
+        }
+        if let Some(access_lifetime) = var("TURTLE_ACCESS_LIFETIME")? {
+            self.auth.access_lifetime = access_lifetime;
+        }
+        if let Some(refresh_lifetime) = var("TURTLE_REFRESH_LIFETIME")? {
+            self.auth.refresh_lifetime = refresh_lifetime;
+        }
+        if let Some(password) = var("TURTLE_SMTP_PASSWORD")? {
+            self.mail.smtp.password = Some(password);
+        }
+        if let Some(url) = var("REDIS_URL")? {
+            self.redis.url = url;
+        }
+        Ok(())
+    }
+    fn arguments(&mut self, arguments: Arguments) {
+        if let Some(hostname) = arguments.hostname {
+            self.server.hostname = hostname;
+        }
+        if let Some(port) = arguments.port {
+            self.server.port = port;
+        }
+        if let Some(url) = arguments.redis_url {
+            self.redis.url = url;
+        }
+        if let Some(certificate) = arguments.certificate {
+            self.tls.get_or_insert_with(TlsConfig::default).certificate = certificate;
+        }
+        if let Some(key) = arguments.key {
+            self.tls.get_or_insert_with(TlsConfig::default).key = key;
+        }
+    }
+}
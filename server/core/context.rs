@@ -1,17 +1,31 @@
-use crate::core::{auth, error, graphql, redis};
+use crate::core::{auth, config, error, graphql, mail, oidc, redis, router, routes};
+use crate::custom;
 
 #[derive(Clone)]
 pub struct Context {
+    pub config: config::Config,
     pub auth: auth::AuthContext,
     pub redis: redis::RedisContext,
+    pub mail: mail::MailContext,
+    pub oidc: oidc::OidcContext,
     pub graphql: graphql::GraphQLContext,
+    pub router: std::sync::Arc<router::Router>,
 }
 impl Context {
-    pub fn new() -> Result<Self, error::Error> {
+    pub async fn new(config: config::Config) -> Result<Self, error::Error> {
+        let mut router = router::Router::new();
+        routes::register(&mut router)?;
+        custom::routes::register(&mut router)?;
+
+        let redis = redis::RedisContext::new(&config.redis)?;
         let instance = Self {
-            auth: auth::AuthContext::new()?,
-            redis: redis::RedisContext::new()?,
+            auth: auth::AuthContext::new(&config.auth, &redis).await?,
+            redis,
+            mail: mail::MailContext::new(&config.mail),
+            oidc: oidc::OidcContext::new(),
             graphql: graphql::GraphQLContext::new()?,
+            router: std::sync::Arc::new(router),
+            config,
         };
         Ok(instance)
     }
@@ -59,6 +59,16 @@ impl From<std::io::Error> for Error {
         Self::new_string(format!("IO error: {}", error))
     }
 }
+impl From<serde_urlencoded::ser::Error> for Error {
+    fn from(error: serde_urlencoded::ser::Error) -> Self {
+        Self::new_string(format!("URL encoding error: {}", error))
+    }
+}
+impl From<serde_urlencoded::de::Error> for Error {
+    fn from(error: serde_urlencoded::de::Error) -> Self {
+        Self::new_string(format!("URL decoding error: {}", error))
+    }
+}
 impl From<hyper::header::InvalidHeaderValue> for Error {
     fn from(error: hyper::header::InvalidHeaderValue) -> Self {
         Self::new_string(format!("Hyper header error: {}", error))
@@ -84,6 +94,11 @@ impl From<std::time::SystemTimeError> for Error {
         Self::new_string(format!("System time error: {}", error))
     }
 }
+impl From<toml::de::Error> for Error {
+    fn from(error: toml::de::Error) -> Self {
+        Self::new_string(format!("TOML error: {}", error))
+    }
+}
 impl From<chrono::ParseError> for Error {
     fn from(error: chrono::ParseError) -> Self {
         Self::new_string(format!("Chrono parse error: {}", error))
@@ -101,9 +116,39 @@ impl From<rsa::pkcs8::Error> for Error {
 }
 impl From<scrypt::password_hash::Error> for Error {
     fn from(error: scrypt::password_hash::Error) -> Self {
+        Self::new_string(format!("Password hash error: {}", error))
+    }
+}
+impl From<scrypt::errors::InvalidParams> for Error {
+    fn from(error: scrypt::errors::InvalidParams) -> Self {
         Self::new_string(format!("Scrypt error: {}", error))
     }
 }
+impl From<argon2::Error> for Error {
+    fn from(error: argon2::Error) -> Self {
+        Self::new_string(format!("Argon2 error: {}", error))
+    }
+}
+impl From<pem::PemError> for Error {
+    fn from(error: pem::PemError) -> Self {
+        Self::new_string(format!("PEM error: {}", error))
+    }
+}
+impl From<ring::error::Unspecified> for Error {
+    fn from(error: ring::error::Unspecified) -> Self {
+        Self::new_string(format!("Ring error: {}", error))
+    }
+}
+impl From<tokio_native_tls::native_tls::Error> for Error {
+    fn from(error: tokio_native_tls::native_tls::Error) -> Self {
+        Self::new_string(format!("TLS error: {}", error))
+    }
+}
+impl<Inner> From<std::sync::PoisonError<Inner>> for Error {
+    fn from(error: std::sync::PoisonError<Inner>) -> Self {
+        Self::new_string(format!("Lock poison error: {}", error))
+    }
+}
 impl<Inner> From<std::sync::TryLockError<Inner>> for Error {
     fn from(error: std::sync::TryLockError<Inner>) -> Self {
         Self::new_string(format!("Try-lock error: {}", error))
@@ -1,4 +1,4 @@
-use crate::core::{context, error, message};
+use crate::core::{auth, context, error, message};
 use crate::custom::schema;
 
 pub struct JuniperContext {
@@ -12,6 +12,16 @@ impl JuniperContext {
     ) -> Self {
         Self { message, global }
     }
+    pub async fn authorize<G: auth::guard::Guard>(
+        &self,
+        guard: G,
+    ) -> Result<auth::Claims, error::Error> {
+        let credentials = {
+            let message = self.message.try_read()?;
+            auth::util::credentials(&message, &self.global)?
+        };
+        auth::guard::authorize(credentials, &self.global, guard).await
+    }
 }
 impl juniper::Context for JuniperContext {}
 
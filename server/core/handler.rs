@@ -1,31 +1,11 @@
-use crate::core::{context, error, message, process, routes};
-
-async fn route(
-    message: &mut message::Message,
-    context: context::Context,
-) -> Result<(), error::Error> {
-    let jwt_regex = regex::Regex::new("/jwt/refresh/?$")?;
-    if jwt_regex.is_match(message.request.uri().path()) {
-        routes::jwt_refresh::handle(message, context).await?;
-        return Ok(());
-    }
-
-    let graphql_regex = regex::Regex::new("/graphql/?$")?;
-    if graphql_regex.is_match(message.request.uri().path()) {
-        routes::gql::handle(message, context).await?;
-        return Ok(());
-    }
-
-    routes::web::handle(message, context).await?;
-    Ok(())
-}
+use crate::core::{context, error, message};
 
 async fn handle_message(
     message: &mut message::Message,
     context: context::Context,
 ) -> Result<(), error::Error> {
-    route(message, context).await?;
-    process::content_type::guess(message).await?;
+    let router = context.router.clone();
+    router.dispatch(message, context).await?;
     Ok(())
 }
 
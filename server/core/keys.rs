@@ -0,0 +1,509 @@
+use crate::core::{config, error, redis, util};
+
+use ::redis::AsyncCommands;
+use rsa::{pkcs8::FromPrivateKey, pkcs8::ToPrivateKey, PublicKeyParts};
+
+/* PEM label of HMAC secrets, asymmetric keys are stored as PKCS#8. */
+const SECRET_TAG: &str = "HMAC SECRET";
+
+/* Adds the first key of a keyring only while it has none. */
+const CREATE_SCRIPT: &str = r"
+    if redis.call('EXISTS', KEYS[1]) == 1 then
+        return 0
+    end
+    redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+    return 1
+";
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/* Key IDs start with the Unix time at which the key may start signing, so */
+/* every replica agrees on the active key without extra metadata. */
+#[derive(Clone)]
+pub struct Keypair {
+    pub kid: String,
+    pub activates: u64,
+    pub algorithm: jsonwebtoken::Algorithm,
+    private: String,
+    encoding: jsonwebtoken::EncodingKey,
+    decoding: jsonwebtoken::DecodingKey,
+    jwk: Option<serde_json::Value>,
+}
+/* Leaves the key material out of logs. */
+impl std::fmt::Debug for Keypair {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter
+            .debug_struct("Keypair")
+            .field("kid", &self.kid)
+            .field("activates", &self.activates)
+            .field("algorithm", &self.algorithm)
+            .finish()
+    }
+}
+impl Keypair {
+    pub(crate) fn generate(
+        activates: u64,
+        algorithm: config::SigningAlgorithm,
+    ) -> Result<Self, error::Error> {
+        use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+        let random = ring::rand::SystemRandom::new();
+        let (tag, contents) = match algorithm {
+            config::SigningAlgorithm::RS256 => {
+                let private_rsa = rsa::RsaPrivateKey::new(&mut rand::rngs::OsRng, 2048)?;
+                ("PRIVATE KEY", private_rsa.to_pkcs8_der()?.as_ref().to_vec())
+            }
+            config::SigningAlgorithm::ES256 => {
+                let document =
+                    EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &random)?;
+                ("PRIVATE KEY", document.as_ref().to_vec())
+            }
+            config::SigningAlgorithm::EdDSA => {
+                let document = Ed25519KeyPair::generate_pkcs8(&random)?;
+                ("PRIVATE KEY", document.as_ref().to_vec())
+            }
+            config::SigningAlgorithm::HS256 => {
+                let mut secret = vec![0u8; 32];
+                ring::rand::SecureRandom::fill(&random, &mut secret)?;
+                (SECRET_TAG, secret)
+            }
+        };
+        let private = pem::encode(&pem::Pem {
+            tag: tag.to_string(),
+            contents,
+        });
+        let kid = format!("{}-{}", activates, &util::uuid()[..8]);
+        Self::from_pem(kid, private)
+    }
+    /* The algorithm follows from the key, so keyrings may mix algorithms */
+    /* while older keys are retired. */
+    fn from_pem(kid: String, private: String) -> Result<Self, error::Error> {
+        use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+        use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair};
+        let activates = match kid.split_once('-') {
+            Some((activates, _)) => activates.parse()?,
+            None => {
+                let message = format!("Invalid key ID {}", kid);
+                return Err(error::Error::new_string(message));
+            }
+        };
+        let document = pem::parse(private.as_bytes())?;
+        let der = document.contents.as_slice();
+        let (algorithm, encoding, decoding, jwk) = if document.tag == SECRET_TAG {
+            let encoding = EncodingKey::from_secret(der);
+            let decoding = DecodingKey::from_secret(der);
+            (Algorithm::HS256, encoding, decoding, None)
+        } else if let Ok(private_rsa) = rsa::RsaPrivateKey::from_pkcs8_der(der) {
+            let public_rsa = rsa::RsaPublicKey::from(&private_rsa);
+            let (n, e) = (public_rsa.n().to_bytes_be(), public_rsa.e().to_bytes_be());
+            let jwk = serde_json::json!({
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": kid,
+                "n": encode(&n),
+                "e": encode(&e),
+            });
+            let encoding = EncodingKey::from_rsa_pem(private.as_bytes())?;
+            let decoding = DecodingKey::from_rsa_raw_components(&n, &e);
+            (Algorithm::RS256, encoding, decoding, Some(jwk))
+        } else if let Ok(keypair) = Ed25519KeyPair::from_pkcs8_maybe_unchecked(der) {
+            let public = keypair.public_key().as_ref();
+            let jwk = serde_json::json!({
+                "kty": "OKP",
+                "use": "sig",
+                "alg": "EdDSA",
+                "kid": kid,
+                "crv": "Ed25519",
+                "x": encode(public),
+            });
+            let encoding = EncodingKey::from_ed_der(der);
+            let decoding = DecodingKey::from_ed_der(public);
+            (Algorithm::EdDSA, encoding, decoding, Some(jwk))
+        } else if let Ok(keypair) =
+            EcdsaKeyPair::from_pkcs8(&ring::signature::ECDSA_P256_SHA256_FIXED_SIGNING, der)
+        {
+            /* An uncompressed point, 0x04 followed by x and y. */
+            let public = keypair.public_key().as_ref();
+            let jwk = serde_json::json!({
+                "kty": "EC",
+                "use": "sig",
+                "alg": "ES256",
+                "kid": kid,
+                "crv": "P-256",
+                "x": encode(&public[1..33]),
+                "y": encode(&public[33..]),
+            });
+            let encoding = EncodingKey::from_ec_der(der);
+            let decoding = DecodingKey::from_ec_der(public);
+            (Algorithm::ES256, encoding, decoding, Some(jwk))
+        } else {
+            let message = format!("Unsupported key type in key {}", kid);
+            return Err(error::Error::new_string(message));
+        };
+        let instance = Self {
+            kid,
+            activates,
+            algorithm,
+            private,
+            encoding,
+            decoding,
+            jwk,
+        };
+        Ok(instance)
+    }
+    pub fn private(&self) -> &str {
+        self.private.as_str()
+    }
+    pub fn encoding_key(&self) -> &jsonwebtoken::EncodingKey {
+        &self.encoding
+    }
+    pub fn decoding_key(&self) -> &jsonwebtoken::DecodingKey {
+        &self.decoding
+    }
+    /* Public key as an RFC 7517 JSON Web Key, None for HMAC secrets. */
+    pub fn jwk(&self) -> Option<&serde_json::Value> {
+        self.jwk.as_ref()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Store {
+    Redis(redis::RedisContext),
+    Files(std::path::PathBuf),
+}
+impl Store {
+    pub fn new(config: &config::KeysConfig, redis: &redis::RedisContext) -> Self {
+        match config.store {
+            config::KeyStore::Redis => Store::Redis(redis.clone()),
+            config::KeyStore::Files => Store::Files(config.directory.clone()),
+        }
+    }
+    /* Returns (kid, private key PEM) pairs. */
+    async fn load(&self, name: &str) -> Result<Vec<(String, String)>, error::Error> {
+        match self {
+            Store::Redis(redis) => {
+                let mut redis_main = redis.main().await?;
+                let keys = redis_main
+                    .hgetall::<_, std::collections::HashMap<String, String>>(format!(
+                        "keys:{}",
+                        name
+                    ))
+                    .await?;
+                Ok(keys.into_iter().collect())
+            }
+            Store::Files(directory) => {
+                let directory = directory.join(name);
+                if !directory.is_dir() {
+                    return Ok(vec![]);
+                }
+                let mut keys = vec![];
+                for entry in std::fs::read_dir(directory)? {
+                    let path = entry?.path();
+                    if path.extension().and_then(|extension| extension.to_str()) != Some("pem") {
+                        continue;
+                    }
+                    if let Some(kid) = path.file_stem().and_then(|stem| stem.to_str()) {
+                        keys.push((kid.to_string(), std::fs::read_to_string(&path)?));
+                    }
+                }
+                Ok(keys)
+            }
+        }
+    }
+    async fn save(&self, name: &str, keypair: &Keypair) -> Result<(), error::Error> {
+        match self {
+            Store::Redis(redis) => {
+                let mut redis_main = redis.main().await?;
+                redis_main
+                    .hset::<_, _, _, ()>(
+                        format!("keys:{}", name),
+                        keypair.kid.as_str(),
+                        keypair.private(),
+                    )
+                    .await?;
+            }
+            Store::Files(directory) => Self::write(&directory.join(name), keypair)?,
+        }
+        Ok(())
+    }
+    /* Saves the first key of a keyring unless another process saved one */
+    /* already, so replicas starting together converge on a single key. */
+    async fn create(&self, name: &str, keypair: &Keypair) -> Result<bool, error::Error> {
+        match self {
+            Store::Redis(redis) => {
+                let mut redis_main = redis.main().await?;
+                Ok(::redis::Script::new(CREATE_SCRIPT)
+                    .key(format!("keys:{}", name))
+                    .arg(keypair.kid.as_str())
+                    .arg(keypair.private())
+                    .invoke_async::<_, bool>(&mut redis_main)
+                    .await?)
+            }
+            Store::Files(directory) => {
+                /* Written aside, then renamed into place, which fails once */
+                /* the keyring directory has a key in it. */
+                let staging = directory.join(format!(".{}-{}", name, keypair.kid));
+                Self::write(&staging, keypair)?;
+                match std::fs::rename(&staging, directory.join(name)) {
+                    Ok(()) => Ok(true),
+                    Err(error)
+                        if error.kind() == std::io::ErrorKind::DirectoryNotEmpty
+                            || error.kind() == std::io::ErrorKind::AlreadyExists =>
+                    {
+                        std::fs::remove_dir_all(&staging)?;
+                        Ok(false)
+                    }
+                    Err(error) => {
+                        std::fs::remove_dir_all(&staging)?;
+                        Err(error.into())
+                    }
+                }
+            }
+        }
+    }
+    fn write(directory: &std::path::Path, keypair: &Keypair) -> Result<(), error::Error> {
+        /* Private keys are readable by the server's user alone. */
+        use std::io::Write;
+        use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(directory)?;
+        let permissions = std::fs::Permissions::from_mode(0o700);
+        std::fs::set_permissions(directory, permissions)?;
+        let path = directory.join(format!("{}.pem", keypair.kid));
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?
+            .write_all(keypair.private().as_bytes())?;
+        Ok(())
+    }
+    async fn remove(&self, name: &str, kid: &str) -> Result<(), error::Error> {
+        match self {
+            Store::Redis(redis) => {
+                let mut redis_main = redis.main().await?;
+                redis_main
+                    .hdel::<_, _, ()>(format!("keys:{}", name), kid)
+                    .await?;
+            }
+            Store::Files(directory) => {
+                let path = directory.join(name).join(format!("{}.pem", kid));
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+    async fn keypairs(&self, name: &str) -> Result<Vec<Keypair>, error::Error> {
+        let mut keypairs = vec![];
+        for (kid, private) in self.load(name).await? {
+            keypairs.push(Keypair::from_pem(kid, private)?);
+        }
+        keypairs.sort_by(|a, b| (a.activates, &a.kid).cmp(&(b.activates, &b.kid)));
+        Ok(keypairs)
+    }
+}
+
+/* All keys of one token type: the newest active key signs and every */
+/* stored key verifies, so tokens survive rotation until they expire. */
+#[derive(Clone, Debug)]
+pub struct Keyring {
+    name: String,
+    keypairs: std::sync::Arc<std::sync::RwLock<Vec<Keypair>>>,
+}
+impl Keyring {
+    pub async fn load(
+        name: &str,
+        algorithm: config::SigningAlgorithm,
+        store: &Store,
+    ) -> Result<Self, error::Error> {
+        let mut keypairs = store.keypairs(name).await?;
+        if keypairs.is_empty() {
+            crate::console_log!("Generating {} {:?} signing key...", name, algorithm);
+            let keypair = Keypair::generate(util::now()?, algorithm)?;
+            if !store.create(name, &keypair).await? {
+                crate::console_log!("Using the {} signing key of another process", name);
+            }
+            keypairs = store.keypairs(name).await?;
+            if keypairs.is_empty() {
+                let message = format!("No {} signing keys found", name);
+                return Err(error::Error::new_string(message));
+            }
+        }
+        let instance = Self {
+            name: name.to_string(),
+            keypairs: std::sync::Arc::new(std::sync::RwLock::new(keypairs)),
+        };
+        Ok(instance)
+    }
+    /* A single key held in memory, for tests signing their own tokens. */
+    #[cfg(test)]
+    pub fn test(algorithm: config::SigningAlgorithm) -> Self {
+        let keypair = Keypair::generate(1, algorithm).unwrap();
+        Self {
+            name: "test".to_string(),
+            keypairs: std::sync::Arc::new(std::sync::RwLock::new(vec![keypair])),
+        }
+    }
+    pub async fn reload(&self, store: &Store) -> Result<(), error::Error> {
+        let keypairs = store.keypairs(self.name.as_str()).await?;
+        if keypairs.is_empty() {
+            let message = format!("No {} signing keys found", self.name);
+            return Err(error::Error::new_string(message));
+        }
+        *self.keypairs.write()? = keypairs;
+        Ok(())
+    }
+    pub fn signing(&self) -> Result<Keypair, error::Error> {
+        let now = util::now()?;
+        let keypairs = self.keypairs.read()?;
+        let active = keypairs
+            .iter()
+            .filter(|keypair| keypair.activates <= now)
+            .max_by_key(|keypair| keypair.activates);
+        match active.or_else(|| keypairs.first()) {
+            Some(keypair) => Ok(keypair.clone()),
+            None => {
+                let message = format!("No {} signing keys loaded", self.name);
+                Err(error::Error::new_string(message))
+            }
+        }
+    }
+    /* Without a kid every key is a candidate, which covers older tokens. */
+    pub fn verifying(&self, kid: Option<&str>) -> Result<Vec<Keypair>, error::Error> {
+        let keypairs = self.keypairs.read()?;
+        let keypairs = keypairs
+            .iter()
+            .filter(|keypair| kid.is_none() || kid == Some(keypair.kid.as_str()))
+            .cloned()
+            .collect();
+        Ok(keypairs)
+    }
+}
+
+/* Periodically picks up keys rotated by other processes. */
+pub fn watch(keyrings: Vec<Keyring>, store: Store, interval: u64) {
+    if interval == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval));
+        loop {
+            interval.tick().await;
+            for keyring in &keyrings {
+                if let Err(error) = keyring.reload(&store).await {
+                    crate::console_warn!("Failed to reload {} keys: {}", keyring.name, error);
+                }
+            }
+        }
+    });
+}
+
+/* Adds a key that starts signing once every replica has reloaded it, and */
+/* drops keys whose successor has been active for longer than `retention`. */
+pub async fn rotate(
+    store: &Store,
+    name: &str,
+    algorithm: config::SigningAlgorithm,
+    delay: u64,
+    retention: u64,
+) -> Result<String, error::Error> {
+    let now = util::now()?;
+    let keypair = Keypair::generate(now + delay, algorithm)?;
+    store.save(name, &keypair).await?;
+
+    let keypairs = store.keypairs(name).await?;
+    for (keypair, successor) in keypairs.iter().zip(keypairs.iter().skip(1)) {
+        if successor.activates + retention < now {
+            store.remove(name, keypair.kid.as_str()).await?;
+        }
+    }
+    Ok(keypair.kid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALGORITHMS: [config::SigningAlgorithm; 4] = [
+        config::SigningAlgorithm::RS256,
+        config::SigningAlgorithm::ES256,
+        config::SigningAlgorithm::EdDSA,
+        config::SigningAlgorithm::HS256,
+    ];
+
+    #[test]
+    fn signs_and_verifies_with_each_algorithm() {
+        let claims = serde_json::json!({ "sub": "user", "exp": util::now().unwrap() + 60 });
+        for algorithm in ALGORITHMS {
+            let keypair = Keypair::generate(1, algorithm).unwrap();
+            let header = jsonwebtoken::Header::new(keypair.algorithm);
+            let token = jsonwebtoken::encode(&header, &claims, keypair.encoding_key()).unwrap();
+
+            /* Also through the stored PEM, as other replicas load it. */
+            let loaded =
+                Keypair::from_pem(keypair.kid.clone(), keypair.private().to_string()).unwrap();
+            assert_eq!(loaded.algorithm, keypair.algorithm);
+            for keypair in [&keypair, &loaded] {
+                let validation = jsonwebtoken::Validation::new(keypair.algorithm);
+                let decoded = jsonwebtoken::decode::<serde_json::Value>(
+                    token.as_str(),
+                    keypair.decoding_key(),
+                    &validation,
+                );
+                assert_eq!(decoded.unwrap().claims["sub"], "user", "{:?}", algorithm);
+            }
+        }
+    }
+
+    #[test]
+    fn publishes_asymmetric_keys_only() {
+        let keypairs = ALGORITHMS
+            .iter()
+            .map(|algorithm| Keypair::generate(1, *algorithm).unwrap())
+            .collect::<Vec<_>>();
+        let keyring = Keyring::test(config::SigningAlgorithm::HS256);
+        keyring.keypairs.write().unwrap().extend(keypairs.clone());
+
+        /* As /.well-known/jwks.json lists them. */
+        let jwks = keyring
+            .verifying(None)
+            .unwrap()
+            .iter()
+            .filter_map(|keypair| keypair.jwk().cloned())
+            .collect::<Vec<_>>();
+        let kids = jwks
+            .iter()
+            .map(|jwk| jwk["kid"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kids,
+            vec![
+                keypairs[0].kid.clone(),
+                keypairs[1].kid.clone(),
+                keypairs[2].kid.clone()
+            ]
+        );
+        assert!(jwks.iter().all(|jwk| jwk.get("k").is_none()));
+    }
+
+    #[tokio::test]
+    async fn first_key_is_created_once() {
+        let directory = std::env::temp_dir().join(format!("turtle-keys-{}", util::uuid()));
+        let store = Store::Files(directory.clone());
+        let algorithm = config::SigningAlgorithm::HS256;
+        let first = Keypair::generate(1, algorithm).unwrap();
+        let second = Keypair::generate(2, algorithm).unwrap();
+
+        assert!(store.create("access", &first).await.unwrap());
+        assert!(!store.create("access", &second).await.unwrap());
+        let keyring = Keyring::load("access", algorithm, &store).await.unwrap();
+        assert_eq!(keyring.signing().unwrap().kid, first.kid);
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
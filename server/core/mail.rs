@@ -0,0 +1,289 @@
+use crate::core::{config, error, util};
+
+/* A bare "local@domain" address of printable ASCII, which keeps it from */
+/* smuggling headers or SMTP commands and from naming another mailbox. */
+pub fn validate(address: &str) -> Result<(), error::Error> {
+    let invalid = || {
+        let message = format!("Invalid email address {:?}", address);
+        Err(error::Error::new_string(message))
+    };
+    let (local, domain) = match address.rsplit_once('@') {
+        Some(parts) => parts,
+        None => return invalid(),
+    };
+    let local_valid = !local.is_empty()
+        && local.len() <= 64
+        && local.chars().all(|character| {
+            character.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.".contains(character)
+        });
+    let domain_valid = !domain.is_empty()
+        && domain.len() <= 253
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|character| character.is_ascii_alphanumeric() || character == '-')
+        });
+    match local_valid && domain_valid {
+        true => Ok(()),
+        false => invalid(),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+impl Email {
+    /* Renders a plain text RFC 5322 message with CRLF line endings. */
+    fn format(&self, from: &str) -> Result<String, error::Error> {
+        for value in &[from, self.to.as_str(), self.subject.as_str()] {
+            if value.contains(['\r', '\n']) {
+                return Err(error::Error::new_str("Line breaks in email headers"));
+            }
+        }
+        let domain = match from.rsplit_once('@') {
+            Some((_, domain)) => domain.trim_end_matches('>'),
+            None => "localhost",
+        };
+        let headers = [
+            format!("From: {}", from),
+            format!("To: {}", self.to),
+            format!("Subject: {}", self.subject),
+            format!("Date: {}", chrono::Utc::now().to_rfc2822()),
+            format!("Message-ID: <{}@{}>", util::uuid(), domain),
+            "MIME-Version: 1.0".to_string(),
+            "Content-Type: text/plain; charset=utf-8".to_string(),
+            "Content-Transfer-Encoding: 8bit".to_string(),
+        ];
+        let body = self.body.replace("\r\n", "\n").replace('\n', "\r\n");
+        Ok(format!("{}\r\n\r\n{}\r\n", headers.join("\r\n"), body))
+    }
+}
+
+#[async_trait::async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, from: &str, email: &Email) -> Result<(), error::Error>;
+}
+
+/* Writes every message to a directory as an .eml file instead of sending it. */
+pub mod outbox {
+    use super::*;
+    pub struct Outbox {
+        directory: std::path::PathBuf,
+    }
+    impl Outbox {
+        pub fn new(directory: std::path::PathBuf) -> Self {
+            Self { directory }
+        }
+    }
+    #[async_trait::async_trait]
+    impl Mailer for Outbox {
+        async fn send(&self, from: &str, email: &Email) -> Result<(), error::Error> {
+            let contents = email.format(from)?;
+            tokio::fs::create_dir_all(&self.directory).await?;
+            let name = format!("{}-{}.eml", util::now()?, util::uuid());
+            let path = self.directory.join(name);
+            tokio::fs::write(&path, contents).await?;
+            crate::console_log!("Wrote email to {} into {:?}", email.to, path);
+            Ok(())
+        }
+    }
+}
+
+pub mod smtp {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt};
+
+    type Stream<S> = tokio::io::BufStream<S>;
+
+    /* Reads a possibly multiline reply and checks its class, e.g. 2xx. */
+    async fn expect<S>(stream: &mut Stream<S>, expected: u16) -> Result<String, error::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await? == 0 {
+                return Err(error::Error::new_str("SMTP connection closed"));
+            }
+            reply.push_str(line.as_str());
+            /* "250-" continues a reply and "250 " ends it. */
+            if line.len() < 4 || line.as_bytes()[3] != b'-' {
+                break;
+            }
+        }
+        let code = reply.get(..3).unwrap_or_default().parse::<u16>()?;
+        if code / 100 != expected / 100 {
+            let message = format!("Unexpected SMTP reply: {}", reply.trim_end());
+            return Err(error::Error::new_string(message));
+        }
+        Ok(reply)
+    }
+    async fn command<S>(
+        stream: &mut Stream<S>,
+        line: &str,
+        expected: u16,
+    ) -> Result<String, error::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        stream.write_all(format!("{}\r\n", line).as_bytes()).await?;
+        stream.flush().await?;
+        expect(stream, expected).await
+    }
+    async fn transaction<S>(
+        stream: &mut Stream<S>,
+        config: &config::SmtpConfig,
+        from: &str,
+        email: &Email,
+    ) -> Result<(), error::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        /* Checked before any command is written, a line break in an address */
+        /* would inject commands into the session. */
+        let address = |mailbox: &str| -> Result<String, error::Error> {
+            let address = match (mailbox.rfind('<'), mailbox.rfind('>')) {
+                (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+                _ => mailbox.trim(),
+            };
+            validate(address)?;
+            Ok(address.to_string())
+        };
+        let (from_address, to_address) = (address(from)?, address(&email.to)?);
+        command(stream, format!("EHLO {}", config.hello).as_str(), 250).await?;
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            let credentials = base64::encode(format!("\0{}\0{}", username, password));
+            command(stream, format!("AUTH PLAIN {}", credentials).as_str(), 235).await?;
+        }
+        command(
+            stream,
+            format!("MAIL FROM:<{}>", from_address).as_str(),
+            250,
+        )
+        .await?;
+        command(stream, format!("RCPT TO:<{}>", to_address).as_str(), 250).await?;
+        command(stream, "DATA", 354).await?;
+        /* Lines starting with a dot are escaped so they cannot end the data. */
+        let data = email
+            .format(from)?
+            .split("\r\n")
+            .map(|line| match line.starts_with('.') {
+                true => format!(".{}", line),
+                false => line.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("\r\n");
+        stream.write_all(data.as_bytes()).await?;
+        command(stream, ".", 250).await?;
+        command(stream, "QUIT", 221).await?;
+        Ok(())
+    }
+
+    pub struct Smtp {
+        config: config::SmtpConfig,
+    }
+    impl Smtp {
+        pub fn new(config: config::SmtpConfig) -> Self {
+            Self { config }
+        }
+        async fn tls(
+            &self,
+            stream: tokio::net::TcpStream,
+        ) -> Result<tokio_native_tls::TlsStream<tokio::net::TcpStream>, error::Error> {
+            let connector = tokio_native_tls::native_tls::TlsConnector::new()?;
+            let connector = tokio_native_tls::TlsConnector::from(connector);
+            Ok(connector.connect(self.config.host.as_str(), stream).await?)
+        }
+        async fn deliver(&self, from: &str, email: &Email) -> Result<(), error::Error> {
+            let config = &self.config;
+            let stream =
+                tokio::net::TcpStream::connect((config.host.as_str(), config.port)).await?;
+            match config.security {
+                config::SmtpSecurity::None => {
+                    let mut stream = Stream::new(stream);
+                    expect(&mut stream, 220).await?;
+                    transaction(&mut stream, config, from, email).await
+                }
+                config::SmtpSecurity::Tls => {
+                    let mut stream = Stream::new(self.tls(stream).await?);
+                    expect(&mut stream, 220).await?;
+                    transaction(&mut stream, config, from, email).await
+                }
+                config::SmtpSecurity::StartTls => {
+                    let mut stream = Stream::new(stream);
+                    expect(&mut stream, 220).await?;
+                    command(&mut stream, format!("EHLO {}", config.hello).as_str(), 250).await?;
+                    command(&mut stream, "STARTTLS", 220).await?;
+                    let mut stream = Stream::new(self.tls(stream.into_inner()).await?);
+                    transaction(&mut stream, config, from, email).await
+                }
+            }
+        }
+    }
+    #[async_trait::async_trait]
+    impl Mailer for Smtp {
+        async fn send(&self, from: &str, email: &Email) -> Result<(), error::Error> {
+            let timeout = std::time::Duration::from_secs(self.config.timeout);
+            match tokio::time::timeout(timeout, self.deliver(from, email)).await {
+                Ok(result) => result,
+                Err(_elapsed) => Err(error::Error::new_str("SMTP delivery timed out")),
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MailContext {
+    from: String,
+    mailer: std::sync::Arc<dyn Mailer>,
+}
+impl MailContext {
+    pub fn new(config: &config::MailConfig) -> Self {
+        crate::console_log!("Creating mail context...");
+
+        let mailer: std::sync::Arc<dyn Mailer> = match config.transport {
+            config::MailTransport::Outbox => {
+                std::sync::Arc::new(outbox::Outbox::new(config.outbox.clone()))
+            }
+            config::MailTransport::Smtp => {
+                std::sync::Arc::new(smtp::Smtp::new(config.smtp.clone()))
+            }
+        };
+        Self {
+            from: config.from.clone(),
+            mailer,
+        }
+    }
+    pub async fn send(&self, email: Email) -> Result<(), error::Error> {
+        validate(email.to.as_str())?;
+        self.mailer.send(self.from.as_str(), &email).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_plain_addresses() {
+        assert!(validate("user@example.com").is_ok());
+        assert!(validate("first.last+tag@mail.example.co").is_ok());
+    }
+    #[test]
+    fn rejects_smtp_injection() {
+        assert!(validate("user@example.com\r\nRCPT TO:<other@example.com>").is_err());
+        assert!(validate("user@example.com>").is_err());
+        assert!(validate("<user@example.com").is_err());
+        assert!(validate("user name@example.com").is_err());
+        assert!(validate("user@").is_err());
+        assert!(validate("@example.com").is_err());
+        assert!(validate("user@-example.com").is_err());
+    }
+}
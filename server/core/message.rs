@@ -5,6 +5,7 @@ pub struct Message {
     pub response: hyper::Response<hyper::Body>,
     pub cookies: cookie::CookieJar,
     pub address: std::net::SocketAddr,
+    pub parameters: std::collections::HashMap<String, String>,
 }
 
 impl Message {
@@ -44,7 +45,8 @@ impl Message {
             request,
             response,
             cookies: self.cookies.clone(),
-            address: self.address.clone(),
+            address: self.address,
+            parameters: self.parameters.clone(),
         }
     }
     pub fn new(
@@ -70,6 +72,7 @@ impl Message {
             response,
             cookies,
             address,
+            parameters: std::collections::HashMap::new(),
         }
     }
     pub fn done(mut self) -> hyper::Response<hyper::Body> {
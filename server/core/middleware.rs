@@ -0,0 +1,657 @@
+use crate::core::{auth, context, error, message, process, router};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    /* Skip the handler and any later layers, the response is final. */
+    Stop,
+}
+
+#[async_trait::async_trait]
+pub trait Middleware: Send + Sync {
+    async fn before(
+        &self,
+        _message: &mut message::Message,
+        _context: &context::Context,
+    ) -> Result<Flow, error::Error> {
+        Ok(Flow::Continue)
+    }
+    async fn after(
+        &self,
+        _message: &mut message::Message,
+        _context: &context::Context,
+    ) -> Result<(), error::Error> {
+        Ok(())
+    }
+}
+
+/* Before hooks run in order and after hooks in reverse, but only for the */
+/* layers whose before hook actually ran. */
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    layers: Vec<std::sync::Arc<dyn Middleware>>,
+}
+impl Pipeline {
+    pub fn new() -> Self {
+        Self { layers: vec![] }
+    }
+    pub fn layer<Layer>(&mut self, layer: Layer) -> &mut Self
+    where
+        Layer: Middleware + 'static,
+    {
+        self.layers.push(std::sync::Arc::new(layer));
+        self
+    }
+    pub async fn before(
+        &self,
+        message: &mut message::Message,
+        context: &context::Context,
+    ) -> Result<(Flow, usize), error::Error> {
+        for (index, layer) in self.layers.iter().enumerate() {
+            if let Flow::Stop = layer.before(message, context).await? {
+                return Ok((Flow::Stop, index + 1));
+            }
+        }
+        Ok((Flow::Continue, self.layers.len()))
+    }
+    pub async fn after(
+        &self,
+        message: &mut message::Message,
+        context: &context::Context,
+        count: usize,
+    ) -> Result<(), error::Error> {
+        for layer in self.layers[..count].iter().rev() {
+            layer.after(message, context).await?;
+        }
+        Ok(())
+    }
+    pub async fn run(
+        &self,
+        message: &mut message::Message,
+        context: context::Context,
+        handler: router::Handler,
+    ) -> Result<(), error::Error> {
+        let (flow, count) = self.before(message, &context).await?;
+        if let Flow::Continue = flow {
+            handler(message, context.clone()).await?;
+        }
+        self.after(message, &context, count).await
+    }
+}
+
+pub mod log {
+    use super::*;
+    struct Start(std::time::Instant);
+
+    pub struct Log;
+    #[async_trait::async_trait]
+    impl Middleware for Log {
+        async fn before(
+            &self,
+            message: &mut message::Message,
+            _context: &context::Context,
+        ) -> Result<Flow, error::Error> {
+            let start = Start(std::time::Instant::now());
+            message.request.extensions_mut().insert(start);
+            Ok(Flow::Continue)
+        }
+        async fn after(
+            &self,
+            message: &mut message::Message,
+            _context: &context::Context,
+        ) -> Result<(), error::Error> {
+            if let Some(Start(start)) = message.request.extensions().get::<Start>() {
+                crate::console_log!(
+                    "{} {} {} ({} ms)",
+                    message.request.method(),
+                    message.request.uri().path(),
+                    message.response.status().as_u16(),
+                    start.elapsed().as_millis()
+                );
+            }
+            Ok(())
+        }
+    }
+}
+
+pub mod content_type {
+    use super::*;
+    pub struct Guess;
+    #[async_trait::async_trait]
+    impl Middleware for Guess {
+        async fn after(
+            &self,
+            message: &mut message::Message,
+            _context: &context::Context,
+        ) -> Result<(), error::Error> {
+            process::content_type::guess(message).await
+        }
+    }
+}
+
+pub mod rate_limit {
+    use super::*;
+    pub struct RateLimit {
+        policy: &'static str,
+    }
+    impl RateLimit {
+        pub fn new(policy: &'static str) -> Self {
+            Self { policy }
+        }
+    }
+    #[async_trait::async_trait]
+    impl Middleware for RateLimit {
+        async fn before(
+            &self,
+            message: &mut message::Message,
+            context: &context::Context,
+        ) -> Result<Flow, error::Error> {
+            let identity = auth::util::rate_limit_identity(message, context, self.policy).await;
+            match auth::util::rate_limit(context, self.policy, identity.as_str()).await {
+                Some(rate_limit) if !rate_limit.allowed => {
+                    *message.response.status_mut() = hyper::StatusCode::TOO_MANY_REQUESTS;
+                    *message.response.body_mut() = hyper::Body::empty();
+                    rate_limit.headers(&mut message.response);
+                    Ok(Flow::Stop)
+                }
+                Some(rate_limit) => {
+                    /* Handlers may replace the response, so headers wait. */
+                    message.request.extensions_mut().insert(rate_limit);
+                    Ok(Flow::Continue)
+                }
+                None => Ok(Flow::Continue),
+            }
+        }
+        async fn after(
+            &self,
+            message: &mut message::Message,
+            _context: &context::Context,
+        ) -> Result<(), error::Error> {
+            if let Some(rate_limit) = message.request.extensions().get::<auth::util::RateLimit>() {
+                /* Handlers may have answered with a stricter limit of their */
+                /* own, e.g. logins within /graphql, which is kept. */
+                let mut limits = hyper::Response::new(hyper::Body::empty());
+                rate_limit.headers(&mut limits);
+                let headers = message.response.headers_mut();
+                for (name, value) in limits.headers() {
+                    if !headers.contains_key(name) {
+                        headers.insert(name, value.clone());
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/* Defence in depth for routes authenticated by cookies: unsafe requests */
+/* must come from a trusted origin and echo the "csrf" cookie in a header, */
+/* which other sites can neither read nor set. */
+pub mod csrf {
+    use super::*;
+    pub const COOKIE: &str = "csrf";
+    pub const HEADER: &str = "x-csrf-token";
+
+    /* Sets the double-submit token unless the browser already has one. */
+    /* Scripts need to read it, so unlike the refresh cookie it is not */
+    /* HttpOnly. Scripts of other origins cannot read the cookie, so the */
+    /* token is also sent in the response header. */
+    pub fn issue(message: &mut message::Message) {
+        let token = match message.cookies.get(COOKIE) {
+            Some(cookie) if !cookie.value().is_empty() => cookie.value().to_string(),
+            _ => {
+                let mut bytes = [0u8; 32];
+                rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut bytes);
+                let token = base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+                let cookie = cookie::Cookie::build(COOKIE, token.clone())
+                    .path("/")
+                    .secure(true)
+                    .same_site(cookie::SameSite::Strict)
+                    .finish();
+                message.cookies.add(cookie);
+                token
+            }
+        };
+        if let Ok(value) = hyper::header::HeaderValue::from_str(token.as_str()) {
+            message.response.headers_mut().insert(HEADER, value);
+        }
+    }
+    pub fn reset(message: &mut message::Message) {
+        let cookie = cookie::Cookie::build(COOKIE, "")
+            .path("/")
+            .secure(true)
+            .same_site(cookie::SameSite::Strict)
+            .finish();
+        message.cookies.remove(cookie);
+    }
+    /* "scheme://authority" of an Origin or Referer header value. */
+    fn origin(value: &str) -> Option<String> {
+        let uri = value.parse::<hyper::Uri>().ok()?;
+        let scheme = uri.scheme_str()?.to_ascii_lowercase();
+        let authority = uri.authority()?.as_str().to_ascii_lowercase();
+        Some(format!("{}://{}", scheme, authority))
+    }
+    /* The origin of the issuer. Without one, the Host header with a scheme */
+    /* from the TLS setting, or from the proxy when trusted to set it. */
+    fn expected(message: &message::Message, context: &context::Context) -> Option<String> {
+        let issuer = auth::util::issuer(message, context);
+        if context.config.auth.issuer.is_some() || !context.config.csrf.trust_forwarded_proto {
+            return origin(issuer.as_str());
+        }
+        let forwarded = message
+            .request
+            .headers()
+            .get("x-forwarded-proto")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(|value| value.trim().to_ascii_lowercase());
+        match forwarded.as_deref() {
+            Some(scheme @ "http") | Some(scheme @ "https") => {
+                let (_, authority) = issuer.split_once("://")?;
+                origin(format!("{}://{}", scheme, authority).as_str())
+            }
+            _ => origin(issuer.as_str()),
+        }
+    }
+    fn trusted(message: &message::Message, context: &context::Context) -> bool {
+        let headers = message.request.headers();
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        /* Browsers send Origin with every cross-origin POST, Referer is */
+        /* the fallback for older ones. Clients sending neither are not */
+        /* browsers, so cannot be driven by another site. */
+        let source = match (
+            header(hyper::header::ORIGIN),
+            header(hyper::header::REFERER),
+        ) {
+            (Some(value), _) => origin(value),
+            (None, Some(value)) => origin(value),
+            (None, None) => return true,
+        };
+        let source = match source {
+            Some(source) => source,
+            None => return false,
+        };
+        Some(&source) == expected(message, context).as_ref()
+            || context
+                .config
+                .csrf
+                .trusted_origins
+                .iter()
+                .any(|trusted| trusted.trim_end_matches('/').eq_ignore_ascii_case(&source))
+    }
+    fn submitted(message: &message::Message) -> bool {
+        let cookie = match message.cookies.get(COOKIE) {
+            Some(cookie) if !cookie.value().is_empty() => cookie.value(),
+            _ => return false,
+        };
+        let header = match message.request.headers().get(HEADER) {
+            Some(header) => header.as_bytes(),
+            None => return false,
+        };
+        ring::constant_time::verify_slices_are_equal(cookie.as_bytes(), header).is_ok()
+    }
+
+    pub struct Csrf;
+    #[async_trait::async_trait]
+    impl Middleware for Csrf {
+        async fn before(
+            &self,
+            message: &mut message::Message,
+            context: &context::Context,
+        ) -> Result<Flow, error::Error> {
+            let safe = matches!(
+                *message.request.method(),
+                hyper::Method::GET | hyper::Method::HEAD | hyper::Method::OPTIONS
+            );
+            if safe || !context.config.csrf.enabled {
+                return Ok(Flow::Continue);
+            }
+            let reason = if !trusted(message, context) {
+                "untrusted origin"
+            } else if !submitted(message) {
+                "missing or mismatched token"
+            } else {
+                return Ok(Flow::Continue);
+            };
+            crate::console_warn!(
+                "CSRF check failed for {} {} from {}: {}",
+                message.request.method(),
+                message.request.uri().path(),
+                message.address.ip(),
+                reason
+            );
+            *message.response.status_mut() = hyper::StatusCode::FORBIDDEN;
+            *message.response.body_mut() = hyper::Body::empty();
+            Ok(Flow::Stop)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn reduces_to_origin() {
+            let referer = "HTTPS://App.Example.com:8443/login?next=/";
+            assert_eq!(
+                origin(referer).as_deref(),
+                Some("https://app.example.com:8443")
+            );
+            assert_eq!(origin("null"), None);
+            assert_eq!(origin("/relative"), None);
+        }
+    }
+}
+
+pub mod security_headers {
+    use super::*;
+    /* The CSP nonce of a request, for handlers that render inline tags. */
+    #[derive(Clone, Debug)]
+    pub struct Nonce(pub String);
+
+    fn nonce() -> Nonce {
+        let mut bytes = [0u8; 16];
+        rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut bytes);
+        Nonce(base64::encode(bytes))
+    }
+
+    /* Prefixes match whole path segments, "/graphql" covers "/graphql/x" */
+    /* but not "/graphqlx". */
+    fn covers(prefix: &str, path: &str) -> bool {
+        let prefix = prefix.trim_end_matches('/');
+        path == prefix
+            || path
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    /* Handlers and inner layers may set their own values, which are kept. */
+    pub struct SecurityHeaders;
+    #[async_trait::async_trait]
+    impl Middleware for SecurityHeaders {
+        async fn before(
+            &self,
+            message: &mut message::Message,
+            context: &context::Context,
+        ) -> Result<Flow, error::Error> {
+            if context.config.security_headers.enabled {
+                message.request.extensions_mut().insert(nonce());
+            }
+            Ok(Flow::Continue)
+        }
+        async fn after(
+            &self,
+            message: &mut message::Message,
+            context: &context::Context,
+        ) -> Result<(), error::Error> {
+            use hyper::header::{self, HeaderValue};
+            let config = &context.config.security_headers;
+            let nonce = match message.request.extensions().get::<Nonce>() {
+                Some(nonce) => nonce.0.clone(),
+                None => return Ok(()),
+            };
+            let path = message.request.uri().path();
+            let route = config
+                .routes
+                .iter()
+                .filter(|(prefix, _route)| covers(prefix, path))
+                .max_by_key(|(prefix, _route)| prefix.len())
+                .map(|(_prefix, route)| route.clone())
+                .unwrap_or_default();
+            let pick = |value: Option<String>, default: &String| match value {
+                Some(value) => value,
+                None => default.clone(),
+            };
+            let mut policy = pick(
+                route.content_security_policy,
+                &config.content_security_policy,
+            )
+            .replace("{nonce}", nonce.as_str());
+            let frame_ancestors = pick(route.frame_ancestors, &config.frame_ancestors);
+            if !frame_ancestors.is_empty() {
+                policy = format!("{}; frame-ancestors {}", policy, frame_ancestors);
+            }
+            let frame_options = match frame_ancestors.as_str() {
+                "'none'" => Some("DENY"),
+                "'self'" => Some("SAMEORIGIN"),
+                _ => None,
+            };
+            let mut values = vec![
+                (header::CONTENT_SECURITY_POLICY, policy),
+                (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+                (
+                    header::REFERRER_POLICY,
+                    pick(route.referrer_policy, &config.referrer_policy),
+                ),
+                (
+                    header::HeaderName::from_static("permissions-policy"),
+                    pick(route.permissions_policy, &config.permissions_policy),
+                ),
+            ];
+            if let Some(frame_options) = frame_options {
+                values.push((header::X_FRAME_OPTIONS, frame_options.to_string()));
+            }
+            /* Browsers ignore HSTS over plain HTTP. */
+            if context.config.tls.is_some() && config.hsts_max_age > 0 {
+                let mut hsts = format!("max-age={}", config.hsts_max_age);
+                if config.hsts_include_subdomains {
+                    hsts.push_str("; includeSubDomains");
+                }
+                if config.hsts_preload {
+                    hsts.push_str("; preload");
+                }
+                values.push((header::STRICT_TRANSPORT_SECURITY, hsts));
+            }
+            let headers = message.response.headers_mut();
+            for (name, value) in values {
+                if value.is_empty() || headers.contains_key(&name) {
+                    continue;
+                }
+                headers.insert(name, HeaderValue::from_str(value.as_str())?);
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn covers_whole_segments() {
+            assert!(covers("/graphql", "/graphql"));
+            assert!(covers("/graphql", "/graphql/schema"));
+            assert!(covers("/graphql/", "/graphql"));
+            assert!(!covers("/graphql", "/graphqlx"));
+            assert!(!covers("/graphql", "/graph"));
+            assert!(covers("/", "/anything"));
+        }
+    }
+}
+
+/* Cross-origin access for other frontends. Preflight requests are answered */
+/* here and never reach the handler or later layers, so add it first. */
+pub mod cors {
+    use super::*;
+    /* Set for allowed actual requests, the after hook adds the headers. */
+    struct Allowed(hyper::header::HeaderValue);
+
+    /* "*" stands for one or more host or port characters. */
+    fn matches(pattern: &str, origin: &str) -> bool {
+        let (prefix, suffix) = match pattern.split_once('*') {
+            Some(parts) => parts,
+            None => return pattern.eq_ignore_ascii_case(origin),
+        };
+        let rest = match origin.get(..prefix.len()) {
+            Some(head) if head.eq_ignore_ascii_case(prefix) => &origin[prefix.len()..],
+            _ => return false,
+        };
+        for (index, character) in rest.char_indices() {
+            if !(character.is_ascii_alphanumeric() || character == '-' || character == '.') {
+                return false;
+            }
+            if matches(suffix, &rest[index + 1..]) {
+                return true;
+            }
+        }
+        false
+    }
+    fn list(values: &[String]) -> Result<hyper::header::HeaderValue, error::Error> {
+        Ok(hyper::header::HeaderValue::from_str(
+            values.join(", ").as_str(),
+        )?)
+    }
+
+    pub struct Cors;
+    #[async_trait::async_trait]
+    impl Middleware for Cors {
+        async fn before(
+            &self,
+            message: &mut message::Message,
+            context: &context::Context,
+        ) -> Result<Flow, error::Error> {
+            use hyper::header;
+            let config = &context.config.cors;
+            let origin = match message.request.headers().get(header::ORIGIN) {
+                Some(origin) => origin.clone(),
+                None => return Ok(Flow::Continue),
+            };
+            let allowed = config
+                .allowed_origins
+                .iter()
+                .any(|pattern| matches(pattern, origin.to_str().unwrap_or_default()));
+            let preflight = message.request.method() == hyper::Method::OPTIONS
+                && message
+                    .request
+                    .headers()
+                    .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+            if !preflight {
+                if allowed {
+                    message.request.extensions_mut().insert(Allowed(origin));
+                }
+                return Ok(Flow::Continue);
+            }
+
+            let headers = message.request.headers();
+            let method = headers
+                .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default();
+            let method_allowed = config
+                .allowed_methods
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(method));
+            let headers_allowed = headers
+                .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .split(',')
+                .map(|name| name.trim())
+                .filter(|name| !name.is_empty())
+                .all(|name| {
+                    config
+                        .allowed_headers
+                        .iter()
+                        .any(|allowed| allowed.eq_ignore_ascii_case(name))
+                });
+            let response = &mut message.response;
+            *response.body_mut() = hyper::Body::empty();
+            let vary = header::HeaderValue::from_static(
+                "Origin, Access-Control-Request-Method, Access-Control-Request-Headers",
+            );
+            response.headers_mut().append(header::VARY, vary);
+            /* Refused preflights carry no CORS headers, so the browser */
+            /* blocks the actual request. */
+            if !(allowed && method_allowed && headers_allowed) {
+                *response.status_mut() = hyper::StatusCode::FORBIDDEN;
+                return Ok(Flow::Stop);
+            }
+            *response.status_mut() = hyper::StatusCode::NO_CONTENT;
+            let headers = response.headers_mut();
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_METHODS,
+                list(&config.allowed_methods)?,
+            );
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_HEADERS,
+                list(&config.allowed_headers)?,
+            );
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, config.max_age.into());
+            if config.allow_credentials {
+                headers.insert(
+                    header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                    header::HeaderValue::from_static("true"),
+                );
+            }
+            Ok(Flow::Stop)
+        }
+        async fn after(
+            &self,
+            message: &mut message::Message,
+            context: &context::Context,
+        ) -> Result<(), error::Error> {
+            use hyper::header;
+            let config = &context.config.cors;
+            if message.request.method() == hyper::Method::OPTIONS
+                && message
+                    .request
+                    .headers()
+                    .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+            {
+                return Ok(());
+            }
+            if message.request.headers().contains_key(header::ORIGIN) {
+                let vary = header::HeaderValue::from_static("Origin");
+                message.response.headers_mut().append(header::VARY, vary);
+            }
+            let origin = match message.request.extensions().get::<Allowed>() {
+                Some(Allowed(origin)) => origin.clone(),
+                None => return Ok(()),
+            };
+            let headers = message.response.headers_mut();
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            if !config.exposed_headers.is_empty() {
+                headers.insert(
+                    header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                    list(&config.exposed_headers)?,
+                );
+            }
+            if config.allow_credentials {
+                headers.insert(
+                    header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                    header::HeaderValue::from_static("true"),
+                );
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn matches_exact_origins() {
+            assert!(matches(
+                "https://app.example.com",
+                "HTTPS://APP.example.com"
+            ));
+            assert!(!matches(
+                "https://app.example.com",
+                "https://app.example.com.evil"
+            ));
+        }
+        #[test]
+        fn matches_host_and_port_patterns() {
+            let pattern = "https://*.example.com";
+            assert!(matches(pattern, "https://admin.example.com"));
+            assert!(matches(pattern, "https://a.b.example.com"));
+            assert!(!matches(pattern, "https://example.com"));
+            assert!(!matches(pattern, "https://evil.com/.example.com"));
+            assert!(!matches(pattern, "http://admin.example.com"));
+            assert!(matches("http://localhost:*", "http://localhost:5173"));
+            assert!(!matches("http://localhost:*", "http://localhost"));
+        }
+    }
+}
@@ -1,12 +1,20 @@
 pub mod auth;
+pub mod config;
 pub mod console;
 pub mod context;
 pub mod error;
 pub mod graphql;
 pub mod handler;
+pub mod keys;
+pub mod mail;
 pub mod message;
+pub mod middleware;
+pub mod oidc;
 pub mod process;
 pub mod redis;
+pub mod router;
 pub mod routes;
 pub mod server;
+pub mod tls;
+pub mod totp;
 pub mod util;
@@ -0,0 +1,565 @@
+use crate::core::{auth, config, context, error};
+
+use sha2::Digest;
+
+type Client = hyper::Client<hyper_tls::HttpsConnector<hyper::client::HttpConnector>>;
+
+const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Clone, Debug, serde::Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    /* RSA modulus and exponent. */
+    n: Option<String>,
+    e: Option<String>,
+    /* EC and OKP public key coordinates. */
+    x: Option<String>,
+    y: Option<String>,
+}
+impl Jwk {
+    /* The decoding key for tokens signed with the algorithm, None when the */
+    /* key is of another type. */
+    fn decoding_key(
+        &self,
+        algorithm: jsonwebtoken::Algorithm,
+    ) -> Option<Result<jsonwebtoken::DecodingKey, error::Error>> {
+        use jsonwebtoken::{Algorithm, DecodingKey};
+        let key = match (
+            algorithm,
+            self.kty.as_str(),
+            &self.n,
+            &self.e,
+            &self.x,
+            &self.y,
+        ) {
+            (
+                Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512,
+                "RSA",
+                Some(n),
+                Some(e),
+                _,
+                _,
+            ) => DecodingKey::from_rsa_components(n, e),
+            (Algorithm::ES256 | Algorithm::ES384, "EC", _, _, Some(x), Some(y)) => {
+                DecodingKey::from_ec_components(x, y)
+            }
+            (Algorithm::EdDSA, "OKP", _, _, Some(x), _) => DecodingKey::from_ed_components(x),
+            _ => return None,
+        };
+        Some(key.map_err(error::Error::from))
+    }
+}
+#[derive(Debug, serde::Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct IdClaims {
+    sub: String,
+    nonce: Option<String>,
+    email: Option<String>,
+    /* Some providers send the string "true". */
+    email_verified: Option<serde_json::Value>,
+}
+
+/* What is kept between the redirect to the provider and its callback. */
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct State {
+    provider: String,
+    nonce: String,
+    verifier: String,
+}
+
+/* An account at an identity provider, as asserted by its ID token. */
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+}
+
+fn random() -> String {
+    let mut bytes = [0u8; 32];
+    rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut bytes);
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn provider<'a>(
+    context: &'a context::Context,
+    name: &str,
+) -> Result<&'a config::OidcProviderConfig, error::Error> {
+    match context.config.auth.oidc.providers.get(name) {
+        Some(provider) => Ok(provider),
+        None => {
+            let message = format!("Unknown identity provider {}", name);
+            Err(error::Error::new_string(message))
+        }
+    }
+}
+
+/* Where the provider sends the browser back, it has to be registered there. */
+pub fn redirect_uri(issuer: &str, name: &str) -> String {
+    format!("{}/auth/oidc/{}/callback", issuer, name)
+}
+
+#[derive(Clone)]
+pub struct OidcContext {
+    client: Client,
+}
+impl OidcContext {
+    pub fn new() -> Self {
+        crate::console_log!("Creating OIDC context...");
+
+        let client = hyper::Client::builder().build(hyper_tls::HttpsConnector::new());
+        Self { client }
+    }
+    async fn request<T>(&self, request: hyper::Request<hyper::Body>) -> Result<T, error::Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let uri = request.uri().to_string();
+        let response = match tokio::time::timeout(TIMEOUT, self.client.request(request)).await {
+            Ok(response) => response?,
+            Err(_elapsed) => {
+                let message = format!("Request to {} timed out", uri);
+                return Err(error::Error::new_string(message));
+            }
+        };
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        if !status.is_success() {
+            let message = format!(
+                "Request to {} failed with {}: {}",
+                uri,
+                status,
+                String::from_utf8_lossy(&body)
+            );
+            return Err(error::Error::new_string(message));
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+    async fn get<T>(&self, uri: &str) -> Result<T, error::Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let request = hyper::Request::get(uri)
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(hyper::Body::empty())?;
+        self.request(request).await
+    }
+    async fn discover(
+        &self,
+        provider: &config::OidcProviderConfig,
+    ) -> Result<Discovery, error::Error> {
+        let issuer = provider.issuer.trim_end_matches('/');
+        let uri = format!("{}/.well-known/openid-configuration", issuer);
+        let discovery = self.get::<Discovery>(uri.as_str()).await?;
+        if discovery.issuer.trim_end_matches('/') != issuer {
+            let message = format!("Provider at {} claims issuer {}", issuer, discovery.issuer);
+            return Err(error::Error::new_string(message));
+        }
+        Ok(discovery)
+    }
+    /* Checks the signature against the provider's published keys, and the */
+    /* issuer, audience, expiry and nonce. RSA, ECDSA and EdDSA signatures */
+    /* are accepted, HMAC would need the client secret as the key. */
+    async fn verify(
+        &self,
+        discovery: &Discovery,
+        provider: &config::OidcProviderConfig,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<IdClaims, error::Error> {
+        use jsonwebtoken::Algorithm;
+        let header = jsonwebtoken::decode_header(id_token)?;
+        if !matches!(
+            header.alg,
+            Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::ES256
+                | Algorithm::ES384
+                | Algorithm::EdDSA
+        ) {
+            let message = format!("Unsupported ID token algorithm {:?}", header.alg);
+            return Err(error::Error::new_string(message));
+        }
+        let mut validation = jsonwebtoken::Validation::new(header.alg);
+        validation.set_issuer(&[discovery.issuer.as_str()]);
+        validation.set_audience(&[provider.client_id.as_str()]);
+
+        let jwks = self.get::<Jwks>(discovery.jwks_uri.as_str()).await?;
+        let mut result = Err(error::Error::new_str("No matching provider key"));
+        for jwk in &jwks.keys {
+            if header.kid.is_some() && jwk.kid != header.kid {
+                continue;
+            }
+            let key = match jwk.decoding_key(header.alg) {
+                Some(Ok(key)) => key,
+                Some(Err(error)) => {
+                    result = Err(error);
+                    continue;
+                }
+                None => continue,
+            };
+            match jsonwebtoken::decode::<IdClaims>(id_token, &key, &validation) {
+                Ok(data) => {
+                    result = Ok(data.claims);
+                    break;
+                }
+                Err(error) => result = Err(error.into()),
+            }
+        }
+        let claims = result?;
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(error::Error::new_str("ID token nonce does not match"));
+        }
+        Ok(claims)
+    }
+    /* The authorization URL, without its state parameter, and what the */
+    /* callback needs to finish. */
+    fn begin(
+        discovery: &Discovery,
+        provider: &config::OidcProviderConfig,
+        name: &str,
+        redirect_uri: &str,
+    ) -> Result<(String, State), error::Error> {
+        let state = State {
+            provider: name.to_string(),
+            nonce: random(),
+            verifier: random(),
+        };
+        let challenge = base64::encode_config(
+            sha2::Sha256::digest(state.verifier.as_bytes()),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let query = serde_urlencoded::to_string([
+            ("response_type", "code"),
+            ("client_id", provider.client_id.as_str()),
+            ("redirect_uri", redirect_uri),
+            ("scope", provider.scopes.join(" ").as_str()),
+            ("nonce", state.nonce.as_str()),
+            ("code_challenge", challenge.as_str()),
+            ("code_challenge_method", "S256"),
+        ])?;
+        let separator = match discovery.authorization_endpoint.contains('?') {
+            true => '&',
+            false => '?',
+        };
+        let url = format!("{}{}{}", discovery.authorization_endpoint, separator, query);
+        Ok((url, state))
+    }
+    /* Returns the provider's authorization URL and the state it will echo. */
+    pub async fn authorize(
+        &self,
+        context: &context::Context,
+        name: &str,
+        redirect_uri: &str,
+    ) -> Result<(String, String), error::Error> {
+        let provider = provider(context, name)?;
+        let discovery = self.discover(provider).await?;
+        let (url, state) = Self::begin(&discovery, provider, name, redirect_uri)?;
+        let lifetime = context.config.auth.oidc.state_lifetime;
+        let value = serde_json::to_string(&state)?;
+        let state = auth::ticket::issue(context, "oidc", value.as_str(), lifetime).await?;
+        let url = format!("{}&state={}", url, state);
+        Ok((url, state))
+    }
+    /* Redeems the state, then finishes with the provider. */
+    pub async fn callback(
+        &self,
+        context: &context::Context,
+        name: &str,
+        redirect_uri: &str,
+        code: &str,
+        state: &str,
+    ) -> Result<Identity, error::Error> {
+        let state = match auth::ticket::redeem(context, "oidc", state).await? {
+            Some(value) => serde_json::from_str::<State>(value.as_str())?,
+            None => return Err(error::Error::new_str("Invalid or expired sign-in state")),
+        };
+        let provider = provider(context, name)?;
+        self.finish(provider, name, redirect_uri, code, state).await
+    }
+    /* Exchanges the code with the PKCE verifier and verifies the ID token. */
+    async fn finish(
+        &self,
+        provider: &config::OidcProviderConfig,
+        name: &str,
+        redirect_uri: &str,
+        code: &str,
+        state: State,
+    ) -> Result<Identity, error::Error> {
+        if state.provider != name {
+            return Err(error::Error::new_str(
+                "Sign-in state is for another provider",
+            ));
+        }
+        let discovery = self.discover(provider).await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", provider.client_id.as_str()),
+            ("code_verifier", state.verifier.as_str()),
+        ];
+        if let Some(client_secret) = &provider.client_secret {
+            form.push(("client_secret", client_secret.as_str()));
+        }
+        let request = hyper::Request::post(discovery.token_endpoint.as_str())
+            .header(
+                hyper::header::CONTENT_TYPE,
+                "application/x-www-form-urlencoded",
+            )
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(hyper::Body::from(serde_urlencoded::to_string(&form)?))?;
+        let tokens = self.request::<TokenResponse>(request).await?;
+
+        let claims = self
+            .verify(
+                &discovery,
+                provider,
+                tokens.id_token.as_str(),
+                state.nonce.as_str(),
+            )
+            .await?;
+        let email_verified = matches!(claims.email_verified, Some(serde_json::Value::Bool(true)))
+            || matches!(
+                claims
+                    .email_verified
+                    .as_ref()
+                    .and_then(|value| value.as_str()),
+                Some("true")
+            );
+        let identity = Identity {
+            provider: name.to_string(),
+            subject: claims.sub,
+            email: claims.email,
+            email_verified,
+        };
+        Ok(identity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::keys;
+    use std::collections::HashMap;
+
+    /* What the provider remembers between authorization and token requests. */
+    struct Grant {
+        challenge: String,
+        nonce: String,
+    }
+    /* A provider on a local port, serving discovery, its keys and a token */
+    /* endpoint that checks the PKCE verifier. */
+    struct Mock {
+        issuer: String,
+        keypair: keys::Keypair,
+        grants: std::sync::Mutex<HashMap<String, Grant>>,
+    }
+    impl Mock {
+        async fn start(algorithm: config::SigningAlgorithm) -> std::sync::Arc<Self> {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let mock = std::sync::Arc::new(Self {
+                issuer: format!("http://{}", address),
+                keypair: keys::Keypair::generate(0, algorithm).unwrap(),
+                grants: std::sync::Mutex::new(HashMap::new()),
+            });
+            let shared = mock.clone();
+            let service = hyper::service::make_service_fn(move |_connection| {
+                let mock = shared.clone();
+                async move {
+                    Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |request| {
+                        let mock = mock.clone();
+                        async move { Ok::<_, std::convert::Infallible>(mock.serve(request).await) }
+                    }))
+                }
+            });
+            let server = hyper::Server::from_tcp(listener).unwrap().serve(service);
+            tokio::spawn(server);
+            mock
+        }
+        fn provider(&self) -> config::OidcProviderConfig {
+            config::OidcProviderConfig {
+                issuer: self.issuer.clone(),
+                client_id: "turtle".to_string(),
+                client_secret: None,
+                scopes: vec!["openid".to_string(), "email".to_string()],
+            }
+        }
+        /* The user signs in at the authorization URL, which is remembered */
+        /* along with the code the provider sends back. */
+        fn approve(&self, url: &str, code: &str) {
+            let query = url.split_once('?').unwrap().1;
+            let query = serde_urlencoded::from_str::<HashMap<String, String>>(query).unwrap();
+            assert_eq!(query["response_type"], "code");
+            assert_eq!(query["client_id"], "turtle");
+            assert_eq!(query["code_challenge_method"], "S256");
+            let grant = Grant {
+                challenge: query["code_challenge"].clone(),
+                nonce: query["nonce"].clone(),
+            };
+            self.grants.lock().unwrap().insert(code.to_string(), grant);
+        }
+        fn json(
+            status: hyper::StatusCode,
+            value: serde_json::Value,
+        ) -> hyper::Response<hyper::Body> {
+            let mut response = hyper::Response::new(hyper::Body::from(value.to_string()));
+            *response.status_mut() = status;
+            response
+        }
+        async fn serve(
+            &self,
+            request: hyper::Request<hyper::Body>,
+        ) -> hyper::Response<hyper::Body> {
+            let ok = hyper::StatusCode::OK;
+            match request.uri().path() {
+                "/.well-known/openid-configuration" => Self::json(
+                    ok,
+                    serde_json::json!({
+                        "issuer": self.issuer,
+                        "authorization_endpoint": format!("{}/authorize", self.issuer),
+                        "token_endpoint": format!("{}/token", self.issuer),
+                        "jwks_uri": format!("{}/jwks", self.issuer),
+                    }),
+                ),
+                "/jwks" => Self::json(ok, serde_json::json!({ "keys": [self.keypair.jwk()] })),
+                "/token" => {
+                    let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                    let form =
+                        serde_urlencoded::from_bytes::<HashMap<String, String>>(&body).unwrap();
+                    let grant = self.grants.lock().unwrap().remove(&form["code"]);
+                    let challenge = base64::encode_config(
+                        sha2::Sha256::digest(form["code_verifier"].as_bytes()),
+                        base64::URL_SAFE_NO_PAD,
+                    );
+                    let grant = match grant {
+                        Some(grant) if grant.challenge == challenge => grant,
+                        _ => {
+                            let error = serde_json::json!({ "error": "invalid_grant" });
+                            return Self::json(hyper::StatusCode::BAD_REQUEST, error);
+                        }
+                    };
+                    let now = crate::core::util::now().unwrap();
+                    let claims = serde_json::json!({
+                        "iss": self.issuer,
+                        "aud": form["client_id"],
+                        "sub": "subject",
+                        "iat": now,
+                        "exp": now + 300,
+                        "nonce": grant.nonce,
+                        "email": "user@example.com",
+                        "email_verified": "true",
+                    });
+                    let mut header = jsonwebtoken::Header::new(self.keypair.algorithm);
+                    header.kid = Some(self.keypair.kid.clone());
+                    let id_token =
+                        jsonwebtoken::encode(&header, &claims, self.keypair.encoding_key())
+                            .unwrap();
+                    Self::json(ok, serde_json::json!({ "id_token": id_token }))
+                }
+                _ => Self::json(hyper::StatusCode::NOT_FOUND, serde_json::json!({})),
+            }
+        }
+    }
+
+    const REDIRECT_URI: &str = "http://localhost/auth/oidc/mock/callback";
+
+    /* Runs the flow up to the callback, as the route and the browser would. */
+    async fn authorize(mock: &Mock, oidc: &OidcContext) -> State {
+        let provider = mock.provider();
+        let discovery = oidc.discover(&provider).await.unwrap();
+        let (url, state) = OidcContext::begin(&discovery, &provider, "mock", REDIRECT_URI).unwrap();
+        assert!(url.starts_with(format!("{}/authorize?", mock.issuer).as_str()));
+        mock.approve(url.as_str(), "code");
+        state
+    }
+    async fn callback(
+        mock: &Mock,
+        oidc: &OidcContext,
+        state: State,
+    ) -> Result<Identity, error::Error> {
+        let provider = mock.provider();
+        oidc.finish(&provider, "mock", REDIRECT_URI, "code", state)
+            .await
+    }
+
+    #[tokio::test]
+    async fn signs_in_with_rs256() {
+        let mock = Mock::start(config::SigningAlgorithm::RS256).await;
+        let oidc = OidcContext::new();
+        let state = authorize(&mock, &oidc).await;
+        let identity = callback(&mock, &oidc, state).await.unwrap();
+        assert_eq!(identity.provider, "mock");
+        assert_eq!(identity.subject, "subject");
+        assert_eq!(identity.email.as_deref(), Some("user@example.com"));
+        assert!(identity.email_verified);
+    }
+    #[tokio::test]
+    async fn signs_in_with_es256() {
+        let mock = Mock::start(config::SigningAlgorithm::ES256).await;
+        let oidc = OidcContext::new();
+        let state = authorize(&mock, &oidc).await;
+        assert!(callback(&mock, &oidc, state).await.is_ok());
+    }
+    #[tokio::test]
+    async fn rejects_state_of_another_provider() {
+        let mock = Mock::start(config::SigningAlgorithm::RS256).await;
+        let oidc = OidcContext::new();
+        let mut state = authorize(&mock, &oidc).await;
+        state.provider = "other".to_string();
+        let error = callback(&mock, &oidc, state).await.unwrap_err();
+        assert!(error.to_string().contains("another provider"));
+    }
+    #[tokio::test]
+    async fn rejects_nonce_mismatch() {
+        let mock = Mock::start(config::SigningAlgorithm::RS256).await;
+        let oidc = OidcContext::new();
+        let mut state = authorize(&mock, &oidc).await;
+        state.nonce = random();
+        let error = callback(&mock, &oidc, state).await.unwrap_err();
+        assert!(error.to_string().contains("nonce"));
+    }
+    #[tokio::test]
+    async fn rejects_pkce_mismatch() {
+        let mock = Mock::start(config::SigningAlgorithm::RS256).await;
+        let oidc = OidcContext::new();
+        let mut state = authorize(&mock, &oidc).await;
+        state.verifier = random();
+        let error = callback(&mock, &oidc, state).await.unwrap_err();
+        assert!(error.to_string().contains("invalid_grant"));
+    }
+    #[tokio::test]
+    async fn rejects_reused_code() {
+        let mock = Mock::start(config::SigningAlgorithm::RS256).await;
+        let oidc = OidcContext::new();
+        let state = authorize(&mock, &oidc).await;
+        let reused = State {
+            provider: state.provider.clone(),
+            nonce: state.nonce.clone(),
+            verifier: state.verifier.clone(),
+        };
+        assert!(callback(&mock, &oidc, state).await.is_ok());
+        assert!(callback(&mock, &oidc, reused).await.is_err());
+    }
+}
@@ -22,7 +22,7 @@ pub mod etag {
         let size = parse_size(metadata);
         format!("W/{}-{}", size, modified)
     }
-    pub fn if_none_match(value: &String, etag: &String) -> bool {
+    pub fn if_none_match(value: &str, etag: &str) -> bool {
         if value.trim() == "*" {
             false
         } else {
@@ -95,8 +95,8 @@ pub mod file {
         message: &mut message::Message,
         path: &std::path::Path,
     ) -> Result<(), error::Error> {
-        let metadata = tokio::fs::metadata(path.clone()).await?;
-        let file = tokio::fs::File::open(path.clone()).await?;
+        let metadata = tokio::fs::metadata(path).await?;
+        let file = tokio::fs::File::open(path).await?;
         let stream = tokio_util::io::ReaderStream::new(file);
 
         let encodings = encodings(message)?;
@@ -148,7 +148,7 @@ pub mod file {
             if mtime.is_some() {
                 if let Ok(value) = value.to_str() {
                     let etag = etag::calculate(&metadata);
-                    if !etag::if_none_match(&value.to_string(), &etag) {
+                    if !etag::if_none_match(value, &etag) {
                         let value = hyper::header::HeaderValue::from_str(etag.as_str())?;
                         message
                             .response
@@ -178,6 +178,38 @@ pub mod file {
     }
 }
 
+pub mod html {
+    use super::*;
+    /* Compiled once, the pattern is fixed. */
+    static TAGS: std::sync::LazyLock<regex::Regex> =
+        std::sync::LazyLock::new(|| regex::Regex::new(r"(?i)<(script|style)\b").unwrap());
+
+    /* Marks the inline and linked scripts and styles of a document as */
+    /* trusted by the Content-Security-Policy nonce. */
+    pub fn nonce(document: &str, nonce: &str) -> String {
+        let replacement = format!("<$1 nonce=\"{}\"", nonce);
+        TAGS.replace_all(document, replacement.as_str())
+            .into_owned()
+    }
+    /* The nonce differs per response, so the document is neither */
+    /* compressed from a stream nor cacheable. */
+    pub async fn send(
+        message: &mut message::Message,
+        path: &std::path::Path,
+        nonce: &str,
+    ) -> Result<(), error::Error> {
+        let document = tokio::fs::read_to_string(path).await?;
+        let document = self::nonce(document.as_str(), nonce);
+        let value = hyper::header::HeaderValue::from_static("no-store");
+        message
+            .response
+            .headers_mut()
+            .insert(hyper::header::CACHE_CONTROL, value);
+        *message.response.body_mut() = hyper::Body::from(document);
+        Ok(())
+    }
+}
+
 pub mod content_type {
     use super::*;
     fn mime_to_header(mime_type: mime::Mime) -> Result<hyper::http::HeaderValue, error::Error> {
@@ -193,6 +225,14 @@ pub mod content_type {
             .append(hyper::header::CONTENT_TYPE, content_type);
         Ok(())
     }
+    pub async fn json(message: &mut message::Message) -> Result<(), error::Error> {
+        let content_type = mime_to_header(mime::APPLICATION_JSON)?;
+        message
+            .response
+            .headers_mut()
+            .insert(hyper::header::CONTENT_TYPE, content_type);
+        Ok(())
+    }
     pub async fn guess(message: &mut message::Message) -> Result<(), error::Error> {
         if message
             .response
@@ -1,4 +1,5 @@
-use crate::core::error;
+use crate::core::{config, error};
+#[allow(unused_imports)]
 pub use redis::AsyncCommands;
 
 pub struct JSONGetParameters {
@@ -8,6 +9,7 @@ pub struct JSONGetParameters {
     noescape: Option<bool>,
     paths: Option<Vec<String>>,
 }
+#[allow(clippy::upper_case_acronyms)]
 pub struct JSON {
     connection: redis::aio::MultiplexedConnection,
 }
@@ -1348,21 +1350,25 @@ impl FTSearchParameters {
         }
     }
 }
+#[allow(dead_code)]
 #[derive(Clone, Debug)]
 struct FTAggregateParametersLoad {
     nargs: String,
     properties: Vec<String>,
 }
+#[allow(dead_code)]
 #[derive(Clone, Debug)]
 struct FTAggregateParametersApply {
     expression: String,
     as_type: String,
 }
+#[allow(dead_code)]
 #[derive(Clone, Debug)]
 struct FTAggregateParametersGroupBy {
     nargs: String,
     properties: Vec<String>,
 }
+#[allow(dead_code)]
 #[derive(Clone, Debug)]
 struct FTAggregateParametersReduce {
     function: String,
@@ -1370,27 +1376,32 @@ struct FTAggregateParametersReduce {
     args: Vec<String>,
     as_type: Option<String>,
 }
+#[allow(dead_code)]
 #[derive(Clone, Debug)]
 struct FTAggregateParametersSortByProperties {
     property: String,
     sort: String,
 }
+#[allow(dead_code)]
 #[derive(Clone, Debug)]
 struct FTAggregateParametersSortBy {
     nargs: String,
     properties: Vec<FTAggregateParametersSortByProperties>,
     max: i32,
 }
+#[allow(dead_code)]
 #[derive(Clone, Debug)]
 struct FTAggregateParametersExpressions {
     expression: String,
     as_type: String,
 }
+#[allow(dead_code)]
 #[derive(Clone, Debug)]
 struct FTAggregateParametersLimit {
     offset: String,
     number_of_results: i32,
 }
+#[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub struct FTAggregateParameters {
     load: Option<FTAggregateParametersLoad>,
@@ -1403,6 +1414,7 @@ pub struct FTAggregateParameters {
     filter: Option<String>,
 }
 
+#[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub struct FTSearchResultItem {
     pub key: String,
@@ -1417,7 +1429,7 @@ impl redis::FromRedisValue for FTSearchResult {
     fn from_redis_value(value: &redis::Value) -> redis::RedisResult<Self> {
         match value {
             redis::Value::Bulk(items) => {
-                if !(items.len() > 1) {
+                if items.len() <= 1 {
                     Ok(FTSearchResult { results: vec![] })
                 } else {
                     let mut results_vec = vec![] as Vec<FTSearchResultItem>;
@@ -1521,19 +1533,19 @@ impl Search {
             if let Some(max_text_fields) = parameters.max_text_fields {
                 cmd.arg("MAXTEXTFIELDS").arg(max_text_fields.to_string());
             }
-            if let Some(_) = parameters.no_offsets {
+            if parameters.no_offsets.is_some() {
                 cmd.arg("NOOFFSETS");
             }
-            if let Some(_) = parameters.temporary {
+            if parameters.temporary.is_some() {
                 cmd.arg("TEMPORARY");
             }
-            if let Some(_) = parameters.nohl {
+            if parameters.nohl.is_some() {
                 cmd.arg("NOHL");
             }
-            if let Some(_) = parameters.no_fields {
+            if parameters.no_fields.is_some() {
                 cmd.arg("NOFIELDS");
             }
-            if let Some(_) = parameters.no_freqs {
+            if parameters.no_freqs.is_some() {
                 cmd.arg("NOFREQS");
             }
             if let Some(stopwords) = parameters.stopwords {
@@ -1786,17 +1798,22 @@ pub struct RedisContext {
 }
 #[allow(dead_code)]
 impl RedisContext {
-    pub fn new() -> Result<Self, error::Error> {
+    pub fn new(config: &config::RedisConfig) -> Result<Self, error::Error> {
         crate::console_log!("Creating Redis context...");
 
-        let url = match std::env::var("REDIS_URL") {
-            Ok(url) => url,
-            Err(_error) => "redis://0.0.0.0:6379".to_string(),
-        };
-        let client = redis::Client::open(url)?;
+        let client = redis::Client::open(config.url.as_str())?;
         let instance = Self { client };
         Ok(instance)
     }
+    /* For tests running scripts against the Redis of REDIS_URL, or of the */
+    /* default configuration. */
+    #[cfg(test)]
+    pub async fn test() -> redis::aio::MultiplexedConnection {
+        let url =
+            std::env::var("REDIS_URL").unwrap_or_else(|_error| config::RedisConfig::default().url);
+        let context = Self::new(&config::RedisConfig { url }).unwrap();
+        context.main().await.unwrap()
+    }
     pub async fn main(&self) -> Result<redis::aio::MultiplexedConnection, error::Error> {
         Ok(self.client.get_multiplexed_tokio_connection().await?)
     }
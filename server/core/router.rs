@@ -0,0 +1,366 @@
+use crate::core::{context, error, message, middleware};
+
+pub type Future<'a> =
+    std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), error::Error>> + Send + 'a>>;
+pub type Handler = for<'a> fn(&'a mut message::Message, context::Context) -> Future<'a>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Static(String),
+    Parameter(String),
+    Wildcard(String),
+}
+impl Segment {
+    /* Static segments outrank parameters, which outrank wildcards. */
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Static(_) => 2,
+            Segment::Parameter(_) => 1,
+            Segment::Wildcard(_) => 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Pattern {
+    segments: Vec<Segment>,
+}
+impl Pattern {
+    fn new(path: &str) -> Result<Self, error::Error> {
+        let mut segments = vec![] as Vec<Segment>;
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            if let Some(Segment::Wildcard(_)) = segments.last() {
+                let message = format!("Wildcard must be the last segment in {}", path);
+                return Err(error::Error::new_string(message));
+            }
+            let segment = if let Some(name) = segment.strip_prefix(':') {
+                Segment::Parameter(name.to_string())
+            } else if let Some(name) = segment.strip_prefix('*') {
+                Segment::Wildcard(name.to_string())
+            } else {
+                Segment::Static(segment.to_string())
+            };
+            segments.push(segment);
+        }
+        Ok(Self { segments })
+    }
+    fn matches(&self, path: &str) -> Option<std::collections::HashMap<String, String>> {
+        let mut parameters = std::collections::HashMap::new();
+        let mut parts = path.split('/').filter(|part| !part.is_empty());
+        for segment in &self.segments {
+            match segment {
+                Segment::Static(name) => {
+                    if parts.next()? != name {
+                        return None;
+                    }
+                }
+                Segment::Parameter(name) => {
+                    parameters.insert(name.clone(), parts.next()?.to_string());
+                }
+                Segment::Wildcard(name) => {
+                    let rest = parts.by_ref().collect::<Vec<_>>().join("/");
+                    parameters.insert(name.clone(), rest);
+                }
+            }
+        }
+        match parts.next() {
+            Some(_) => None,
+            None => Some(parameters),
+        }
+    }
+    fn rank(&self) -> Vec<u8> {
+        let mut rank = self.segments.iter().map(Segment::rank).collect::<Vec<_>>();
+        /* An exact end outranks a wildcard that would match the same path. */
+        if !matches!(self.segments.last(), Some(Segment::Wildcard(_))) {
+            rank.push(1);
+        }
+        rank
+    }
+}
+
+struct Endpoint {
+    method: hyper::Method,
+    handler: Handler,
+    pipeline: middleware::Pipeline,
+}
+
+struct Route {
+    pattern: Pattern,
+    endpoints: Vec<Endpoint>,
+}
+impl Route {
+    fn allow(&self) -> String {
+        let mut methods = self
+            .endpoints
+            .iter()
+            .map(|endpoint| endpoint.method.as_str())
+            .collect::<Vec<_>>();
+        if methods.contains(&"GET") && !methods.contains(&"HEAD") {
+            methods.push("HEAD");
+        }
+        if !methods.contains(&"OPTIONS") {
+            methods.push("OPTIONS");
+        }
+        methods.join(", ")
+    }
+}
+
+/* Answers OPTIONS for routes that do not register it, the Allow header is */
+/* set beforehand. */
+fn options(message: &mut message::Message, _context: context::Context) -> Future<'_> {
+    Box::pin(async move {
+        *message.response.status_mut() = hyper::StatusCode::NO_CONTENT;
+        *message.response.body_mut() = hyper::Body::empty();
+        Ok(())
+    })
+}
+
+pub struct Router {
+    routes: Vec<Route>,
+    pipeline: middleware::Pipeline,
+}
+#[allow(dead_code)]
+impl Router {
+    pub fn new() -> Self {
+        Self {
+            routes: vec![],
+            pipeline: middleware::Pipeline::new(),
+        }
+    }
+    /* Global layers wrap every request, including 404 and 405 responses. */
+    pub fn layer<Layer>(&mut self, layer: Layer) -> &mut Self
+    where
+        Layer: middleware::Middleware + 'static,
+    {
+        self.pipeline.layer(layer);
+        self
+    }
+    pub fn group(&mut self, prefix: &str) -> Group<'_> {
+        Group {
+            router: self,
+            prefix: prefix.trim_end_matches('/').to_string(),
+            pipeline: middleware::Pipeline::new(),
+        }
+    }
+    fn insert(
+        &mut self,
+        method: hyper::Method,
+        path: &str,
+        handler: Handler,
+        pipeline: middleware::Pipeline,
+    ) -> Result<(), error::Error> {
+        let pattern = Pattern::new(path)?;
+        let endpoint = Endpoint {
+            method,
+            handler,
+            pipeline,
+        };
+        match self
+            .routes
+            .iter_mut()
+            .find(|route| route.pattern == pattern)
+        {
+            Some(route) => {
+                if route
+                    .endpoints
+                    .iter()
+                    .any(|existing| existing.method == endpoint.method)
+                {
+                    let message =
+                        format!("Route {} {} is already registered", endpoint.method, path);
+                    return Err(error::Error::new_string(message));
+                }
+                route.endpoints.push(endpoint);
+            }
+            None => {
+                let route = Route {
+                    pattern,
+                    endpoints: vec![endpoint],
+                };
+                self.routes.push(route);
+                /* Keep the most specific patterns first so they win. */
+                self.routes
+                    .sort_by_key(|route| std::cmp::Reverse(route.pattern.rank()));
+            }
+        }
+        Ok(())
+    }
+    /* Paths are split on "/" and may contain ":name" parameters and one */
+    /* trailing "*name" wildcard, which are exposed on Message::parameters. */
+    pub fn route(
+        &mut self,
+        method: hyper::Method,
+        path: &str,
+        handler: Handler,
+    ) -> Result<&mut Self, error::Error> {
+        self.insert(method, path, handler, middleware::Pipeline::new())?;
+        Ok(self)
+    }
+    pub fn get(&mut self, path: &str, handler: Handler) -> Result<&mut Self, error::Error> {
+        self.route(hyper::Method::GET, path, handler)
+    }
+    pub fn post(&mut self, path: &str, handler: Handler) -> Result<&mut Self, error::Error> {
+        self.route(hyper::Method::POST, path, handler)
+    }
+    async fn route_message(
+        &self,
+        message: &mut message::Message,
+        context: context::Context,
+    ) -> Result<(), error::Error> {
+        let path = message.request.uri().path().to_string();
+        for route in &self.routes {
+            if let Some(parameters) = route.pattern.matches(path.as_str()) {
+                /* HEAD falls back to GET, dispatch drops the body. */
+                let method = message.request.method();
+                let endpoint = route
+                    .endpoints
+                    .iter()
+                    .find(|endpoint| endpoint.method == method)
+                    .or_else(|| match *method == hyper::Method::HEAD {
+                        true => route
+                            .endpoints
+                            .iter()
+                            .find(|endpoint| endpoint.method == hyper::Method::GET),
+                        false => None,
+                    });
+                let allow = hyper::header::HeaderValue::from_str(route.allow().as_str())?;
+                return match endpoint {
+                    Some(endpoint) => {
+                        message.parameters = parameters;
+                        endpoint
+                            .pipeline
+                            .run(message, context, endpoint.handler)
+                            .await
+                    }
+                    /* Runs the layers of the route's first endpoint, so that */
+                    /* CORS can answer preflight requests. */
+                    None if message.request.method() == hyper::Method::OPTIONS => {
+                        message.parameters = parameters;
+                        message
+                            .response
+                            .headers_mut()
+                            .insert(hyper::header::ALLOW, allow);
+                        route.endpoints[0]
+                            .pipeline
+                            .run(message, context, options)
+                            .await
+                    }
+                    None => {
+                        *message.response.status_mut() = hyper::StatusCode::METHOD_NOT_ALLOWED;
+                        *message.response.body_mut() = hyper::Body::empty();
+                        message
+                            .response
+                            .headers_mut()
+                            .insert(hyper::header::ALLOW, allow);
+                        Ok(())
+                    }
+                };
+            }
+        }
+        *message.response.status_mut() = hyper::StatusCode::NOT_FOUND;
+        *message.response.body_mut() = hyper::Body::empty();
+        Ok(())
+    }
+    pub async fn dispatch(
+        &self,
+        message: &mut message::Message,
+        context: context::Context,
+    ) -> Result<(), error::Error> {
+        let (flow, count) = self.pipeline.before(message, &context).await?;
+        if let middleware::Flow::Continue = flow {
+            self.route_message(message, context.clone()).await?;
+        }
+        self.pipeline.after(message, &context, count).await?;
+        /* Answers HEAD with the headers of GET, the length included. */
+        if message.request.method() == hyper::Method::HEAD {
+            let length = hyper::body::HttpBody::size_hint(message.response.body()).exact();
+            let headers = message.response.headers_mut();
+            if let (Some(length), false) =
+                (length, headers.contains_key(hyper::header::CONTENT_LENGTH))
+            {
+                headers.insert(hyper::header::CONTENT_LENGTH, length.into());
+            }
+            *message.response.body_mut() = hyper::Body::empty();
+        }
+        Ok(())
+    }
+}
+
+/* Routes registered through a group share its path prefix and layers. */
+pub struct Group<'a> {
+    router: &'a mut Router,
+    prefix: String,
+    pipeline: middleware::Pipeline,
+}
+#[allow(dead_code)]
+impl<'a> Group<'a> {
+    pub fn layer<Layer>(&mut self, layer: Layer) -> &mut Self
+    where
+        Layer: middleware::Middleware + 'static,
+    {
+        self.pipeline.layer(layer);
+        self
+    }
+    pub fn route(
+        &mut self,
+        method: hyper::Method,
+        path: &str,
+        handler: Handler,
+    ) -> Result<&mut Self, error::Error> {
+        let path = format!("{}{}", self.prefix, path);
+        let pipeline = self.pipeline.clone();
+        self.router
+            .insert(method, path.as_str(), handler, pipeline)?;
+        Ok(self)
+    }
+    pub fn get(&mut self, path: &str, handler: Handler) -> Result<&mut Self, error::Error> {
+        self.route(hyper::Method::GET, path, handler)
+    }
+    pub fn post(&mut self, path: &str, handler: Handler) -> Result<&mut Self, error::Error> {
+        self.route(hyper::Method::POST, path, handler)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(path: &str, methods: &[hyper::Method]) -> Route {
+        let endpoints = methods
+            .iter()
+            .map(|method| Endpoint {
+                method: method.clone(),
+                handler: options,
+                pipeline: middleware::Pipeline::new(),
+            })
+            .collect();
+        Route {
+            pattern: Pattern::new(path).unwrap(),
+            endpoints,
+        }
+    }
+    #[test]
+    fn matches_parameters_and_wildcards() {
+        let pattern = Pattern::new("/users/:id/*rest").unwrap();
+        let parameters = pattern.matches("/users/42/a/b").unwrap();
+        assert_eq!(parameters["id"], "42");
+        assert_eq!(parameters["rest"], "a/b");
+        assert!(pattern.matches("/users").is_none());
+        assert!(Pattern::new("/*rest/more").is_err());
+    }
+    #[test]
+    fn ranks_static_over_parameters() {
+        let exact = Pattern::new("/users/me").unwrap();
+        let parameter = Pattern::new("/users/:id").unwrap();
+        let wildcard = Pattern::new("/users/*rest").unwrap();
+        assert!(exact.rank() > parameter.rank());
+        assert!(parameter.rank() > wildcard.rank());
+    }
+    #[test]
+    fn allows_head_with_get() {
+        let get = route("/", &[hyper::Method::GET, hyper::Method::POST]);
+        assert_eq!(get.allow(), "GET, POST, HEAD, OPTIONS");
+        let post = route("/", &[hyper::Method::POST]);
+        assert_eq!(post.allow(), "POST, OPTIONS");
+    }
+}
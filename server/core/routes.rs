@@ -1,33 +1,105 @@
-use crate::core::{auth, context, error, graphql, message, process};
+use crate::core::{auth, context, error, graphql, message, middleware, process, router};
 use crate::custom::jwt;
 
+pub fn register(router: &mut router::Router) -> Result<(), error::Error> {
+    router
+        .layer(middleware::log::Log)
+        .layer(middleware::security_headers::SecurityHeaders)
+        .layer(middleware::content_type::Guess);
+    router
+        .group("/jwt")
+        .layer(middleware::cors::Cors)
+        .layer(middleware::rate_limit::RateLimit::new("refresh"))
+        .layer(middleware::csrf::Csrf)
+        .post("/refresh", |message, context| {
+            Box::pin(jwt_refresh::post(message, context))
+        })?;
+    router
+        .group("/.well-known")
+        .get("/jwks.json", |message, context| {
+            Box::pin(well_known::jwks(message, context))
+        })?
+        .get("/openid-configuration", |message, context| {
+            Box::pin(well_known::openid_configuration(message, context))
+        })?;
+    router
+        .group("/graphql")
+        .layer(middleware::cors::Cors)
+        .layer(middleware::rate_limit::RateLimit::new("graphql"))
+        .get("/", |message, context| Box::pin(gql::get(message, context)))?
+        .post("/", |message, context| {
+            Box::pin(gql::post(message, context))
+        })?;
+    router.get("/*path", |message, context| {
+        Box::pin(web::get(message, context))
+    })?;
+    Ok(())
+}
+
 pub mod jwt_refresh {
     use super::*;
     use auth::Token;
-    async fn post(
+    pub async fn post(
         message: &mut message::Message,
         context: context::Context,
     ) -> Result<(), error::Error> {
         match message.cookies.get("refresh") {
             Some(refresh) => {
                 /* Extract claims found in the cookie. */
-                match context.auth.refresh.verify(refresh.value().to_string()) {
+                let issuer = auth::util::issuer(message, &context);
+                match context
+                    .auth
+                    .refresh
+                    .verify(refresh.value().to_string(), issuer.as_str())
+                {
                     Ok(claims) => {
-                        /* Extract claims from Redis */
-                        let mut json = context.redis.json().await?;
-                        let result = json.get(claims.sub.clone(), None, None).await?;
-                        let user = serde_json::from_str::<jwt::Payload>(result.as_str())?;
-
-                        /* If the refresh token is valid, then create an access token. */
-                        let access_token = if user.jti == claims.jti {
-                            context.auth.refresh.create(user.clone(), message)?;
-                            *message.response.status_mut() = hyper::StatusCode::OK;
-                            context.auth.access.create(user, message)?
-                        } else {
-                            "".to_string()
-                        };
-                        let json = serde_json::json!({ "token": access_token });
-                        *message.response.body_mut() = hyper::Body::from(json.to_string());
+                        match auth::revocation::rotate(&context, &claims, message.address.ip())
+                            .await?
+                        {
+                            /* The token was current, issue its successor and an access token. */
+                            auth::revocation::Refresh::Rotated(jti) => {
+                                /* Extract claims from Redis */
+                                let mut json = context.redis.json().await?;
+                                let result = json.get(claims.sub.clone(), None, None).await?;
+                                let user = serde_json::from_str::<jwt::Payload>(result.as_str())?;
+                                let roles = jwt::roles(
+                                    &context.config.auth,
+                                    &user.email,
+                                    user.email_verified,
+                                    &user.roles,
+                                );
+                                let user = jwt::Payload {
+                                    jti: Some(jti),
+                                    roles,
+                                    ..user
+                                };
+                                context.auth.refresh.create(
+                                    user.clone(),
+                                    issuer.as_str(),
+                                    message,
+                                )?;
+                                *message.response.status_mut() = hyper::StatusCode::OK;
+                                let access_token =
+                                    context.auth.access.create(user, issuer.as_str(), message)?;
+                                let json = serde_json::json!({ "token": access_token });
+                                *message.response.body_mut() = hyper::Body::from(json.to_string());
+                            }
+                            auth::revocation::Refresh::Reused => {
+                                /* Logged in release builds too, this usually means a stolen token. */
+                                crate::console_error!(
+                                    "Security: refresh token {} of {} replayed from {}, family revoked",
+                                    claims.jti.unwrap_or_default(),
+                                    claims.sub,
+                                    message.address.ip()
+                                );
+                                context.auth.refresh.reset(message);
+                                *message.response.status_mut() = hyper::StatusCode::FORBIDDEN;
+                            }
+                            auth::revocation::Refresh::Revoked => {
+                                context.auth.refresh.reset(message);
+                                *message.response.status_mut() = hyper::StatusCode::FORBIDDEN;
+                            }
+                        }
                     }
                     Err(_error) => {
                         *message.response.status_mut() = hyper::StatusCode::FORBIDDEN;
@@ -40,24 +112,74 @@ pub mod jwt_refresh {
         }
         Ok(())
     }
-    pub async fn handle(
+}
+
+pub mod well_known {
+    use super::*;
+    use auth::Token;
+    /* Publish keys for a reload interval, so rotated keys appear in time. */
+    fn cache(
+        message: &mut message::Message,
+        context: &context::Context,
+    ) -> Result<(), error::Error> {
+        let max_age = context.config.auth.keys.reload_interval;
+        let value =
+            hyper::header::HeaderValue::from_str(format!("public, max-age={}", max_age).as_str())?;
+        message
+            .response
+            .headers_mut()
+            .insert(hyper::header::CACHE_CONTROL, value);
+        Ok(())
+    }
+    pub async fn jwks(
         message: &mut message::Message,
         context: context::Context,
     ) -> Result<(), error::Error> {
-        match *message.request.method() {
-            hyper::Method::POST => post(message, context).await,
-            _ => {
-                *message.response.status_mut() = hyper::StatusCode::METHOD_NOT_ALLOWED;
-                *message.response.body_mut() = hyper::Body::empty();
-                Ok(())
-            }
+        /* Pending and retiring keys are listed along with the signing key. */
+        let keys = context
+            .auth
+            .access
+            .keyring()
+            .verifying(None)?
+            .iter()
+            .filter_map(|keypair| keypair.jwk().cloned())
+            .collect::<Vec<_>>();
+        let json = serde_json::json!({ "keys": keys });
+        *message.response.body_mut() = hyper::Body::from(json.to_string());
+        *message.response.status_mut() = hyper::StatusCode::OK;
+        process::content_type::json(message).await?;
+        cache(message, &context)
+    }
+    pub async fn openid_configuration(
+        message: &mut message::Message,
+        context: context::Context,
+    ) -> Result<(), error::Error> {
+        let issuer = auth::util::issuer(message, &context);
+        /* Access tokens are not ID tokens and are not obtained through an */
+        /* OAuth flow, so only what resource servers need is advertised. */
+        let json = serde_json::json!({
+            "issuer": issuer,
+            "jwks_uri": format!("{}/.well-known/jwks.json", issuer),
+        });
+        *message.response.body_mut() = hyper::Body::from(json.to_string());
+        *message.response.status_mut() = hyper::StatusCode::OK;
+        process::content_type::json(message).await?;
+        /* An issuer taken from the Host header must not be cached for */
+        /* other clients. */
+        if context.config.auth.issuer.is_none() {
+            message.response.headers_mut().insert(
+                hyper::header::CACHE_CONTROL,
+                hyper::header::HeaderValue::from_static("private, no-store"),
+            );
+            return Ok(());
         }
+        cache(message, &context)
     }
 }
 
 pub mod gql {
     use super::*;
-    async fn get(
+    pub async fn get(
         message: &mut message::Message,
         _context: context::Context,
     ) -> Result<(), error::Error> {
@@ -65,12 +187,16 @@ pub mod gql {
         message.response = response;
         Ok(())
     }
-    async fn post(
+    pub async fn post(
         message: &mut message::Message,
         context: context::Context,
     ) -> Result<(), error::Error> {
+        /* Starts without response headers, so whatever the resolvers set */
+        /* can be told apart and copied back below. */
+        let mut scratch = message.clone().await;
+        scratch.response.headers_mut().clear();
         let juniper_context = std::sync::Arc::new(graphql::JuniperContext::new(
-            std::sync::Arc::new(std::sync::RwLock::new(message.clone().await)),
+            std::sync::Arc::new(std::sync::RwLock::new(scratch)),
             context.clone(),
         ));
         let response = juniper_hyper::graphql(
@@ -86,27 +212,26 @@ pub mod gql {
             }
         }
         message.response = response;
-        Ok(())
-    }
-    pub async fn handle(
-        message: &mut message::Message,
-        context: context::Context,
-    ) -> Result<(), error::Error> {
-        match *message.request.method() {
-            hyper::Method::GET => get(message, context).await,
-            hyper::Method::POST => post(message, context).await,
-            _ => {
-                *message.response.status_mut() = hyper::StatusCode::METHOD_NOT_ALLOWED;
-                *message.response.body_mut() = hyper::Body::empty();
-                Ok(())
+        {
+            /* Resolvers may set headers, e.g. rate limits on login. Their */
+            /* values replace the response's, every one of them is kept. */
+            let juniper_message = juniper_context.message.try_read()?;
+            let resolved = juniper_message.response.headers();
+            let headers = message.response.headers_mut();
+            for name in resolved.keys() {
+                headers.remove(name);
+                for value in resolved.get_all(name) {
+                    headers.append(name, value.clone());
+                }
             }
         }
+        Ok(())
     }
 }
 
 pub mod web {
     use super::*;
-    async fn get(
+    pub async fn get(
         message: &mut message::Message,
         _context: context::Context,
     ) -> Result<(), error::Error> {
@@ -131,22 +256,19 @@ pub mod web {
             }
         };
 
-        process::file::send(message, &path).await?;
+        let nonce = message
+            .request
+            .extensions()
+            .get::<middleware::security_headers::Nonce>()
+            .cloned();
+        match nonce {
+            Some(nonce) if path == dist_root.join("index.html") => {
+                process::html::send(message, &path, nonce.0.as_str()).await?
+            }
+            _ => process::file::send(message, &path).await?,
+        }
 
         *message.response.status_mut() = hyper::StatusCode::OK;
         Ok(())
     }
-    pub async fn handle(
-        message: &mut message::Message,
-        context: context::Context,
-    ) -> Result<(), error::Error> {
-        match *message.request.method() {
-            hyper::Method::GET => get(message, context).await,
-            _ => {
-                *message.response.status_mut() = hyper::StatusCode::METHOD_NOT_ALLOWED;
-                *message.response.body_mut() = hyper::Body::empty();
-                Ok(())
-            }
-        }
-    }
 }
@@ -1,18 +1,23 @@
-use crate::core::{context, handler};
+use crate::core::{config, context, error, handler, tls};
 
-type Address = [u8; 4];
+type Shutdown = tokio::sync::watch::Receiver<()>;
+
+#[derive(Clone, Copy, Debug)]
+enum Protocol {
+    Http1,
+    Http2,
+    Detect,
+}
 
 pub struct Server {
     context: context::Context,
     active: bool,
-    hostname: Address,
 }
 impl Server {
-    pub fn new(context: context::Context, hostname: Address) -> Self {
+    pub fn new(context: context::Context) -> Self {
         Self {
             context,
             active: false,
-            hostname,
         }
     }
     async fn abort_signal() {
@@ -23,33 +28,185 @@ impl Server {
             }
         }
     }
-    pub async fn serve(&mut self) {
-        /* Prevent the server from serving twice. */
-        if self.active {
-            return;
+    fn alpn_protocols(config: &config::Http2Config) -> Vec<Vec<u8>> {
+        match config.enabled {
+            true => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            false => vec![b"http/1.1".to_vec()],
         }
-        self.active = true;
+    }
+    fn http(config: &config::Http2Config, protocol: Protocol) -> hyper::server::conn::Http {
+        let mut http = hyper::server::conn::Http::new();
+        match protocol {
+            Protocol::Http1 => {
+                http.http1_only(true);
+            }
+            Protocol::Http2 => {
+                http.http2_only(true);
+            }
+            /* Hyper serves HTTP/1 and switches on seeing the HTTP/2 preface. */
+            Protocol::Detect => (),
+        }
+        http.http2_max_concurrent_streams(config.max_concurrent_streams)
+            .http2_initial_stream_window_size(config.initial_stream_window_size)
+            .http2_initial_connection_window_size(config.initial_connection_window_size)
+            .http2_adaptive_window(config.adaptive_window);
+        http
+    }
+    async fn connection<Stream>(
+        stream: Stream,
+        protocol: Protocol,
+        address: std::net::SocketAddr,
+        context: context::Context,
+        mut shutdown: Shutdown,
+    ) where
+        Stream: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        let http = Self::http(&context.config.http2, protocol);
+        let service_fn = move |request| handler::handle(request, address, context.clone());
+        let service = hyper::service::service_fn(service_fn);
+        let connection = http.serve_connection(stream, service);
+        tokio::pin!(connection);
 
-        let context = self.context.clone();
-        let make_service_fn = move |conn: &hyper::server::conn::AddrStream| {
-            let context = context.clone();
-            let address = conn.remote_addr();
-            let service_fn = move |request| handler::handle(request, address, context.clone());
+        /* Let in-flight requests finish once the server starts shutting down. */
+        let result = tokio::select! {
+            result = &mut connection => result,
+            _ = shutdown.changed() => {
+                connection.as_mut().graceful_shutdown();
+                connection.await
+            }
+        };
+        if let Err(error) = result {
+            crate::console_warn!("Connection to {} failed: {}", address, error);
+        }
+    }
+    fn redirect(&self, port: u16, mut shutdown: Shutdown) -> Result<(), error::Error> {
+        let https_port = self.context.config.server.port;
+        let make_service_fn = move |_conn: &hyper::server::conn::AddrStream| {
+            let service_fn = move |request| tls::redirect::handle(request, https_port);
             let service = hyper::service::service_fn(service_fn);
 
             async move { Ok::<_, std::convert::Infallible>(service) }
         };
 
         let make_service = hyper::service::make_service_fn(make_service_fn);
-        let addr = std::net::SocketAddr::from((self.hostname, 3080));
-        let future = hyper::Server::bind(&addr)
+        let addr = std::net::SocketAddr::new(self.context.config.server.hostname, port);
+        let future = hyper::Server::try_bind(&addr)?
             .serve(make_service)
-            .with_graceful_shutdown(Self::abort_signal());
+            .with_graceful_shutdown(async move {
+                let _ = shutdown.changed().await;
+            });
+        tokio::spawn(async move {
+            if let Err(error) = future.await {
+                crate::console_error!("Failed to start redirect server: {}", error);
+            }
+        });
+        crate::console_log!(
+            "Redirecting {} to HTTPS",
+            format!("http://{}", addr).magenta().underline()
+        );
+        Ok(())
+    }
+    async fn listen(&self) -> Result<(), error::Error> {
+        let config = &self.context.config;
+        let addr = std::net::SocketAddr::new(config.server.hostname, config.server.port);
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        let acceptor = match &config.tls {
+            Some(tls_config) => {
+                let alpn_protocols = Self::alpn_protocols(&config.http2);
+                Some(tls::acceptor(tls_config, alpn_protocols)?)
+            }
+            None => None,
+        };
+
+        let (shutdown_sender, shutdown) = tokio::sync::watch::channel(());
+        if let Some(port) = config.tls.as_ref().and_then(|tls| tls.redirect_port) {
+            self.redirect(port, shutdown.clone())?;
+        }
+
+        let scheme = match acceptor {
+            Some(_) => "https",
+            None => "http",
+        };
         crate::console_log!(
             "Server is running on {}",
-            "http://localhost:3080".magenta().underline()
+            format!("{}://{}", scheme, addr).magenta().underline()
+        );
+
+        /* A client stalling the handshake would otherwise hold the */
+        /* shutdown receiver, and the drain below, forever. */
+        let handshake_timeout = std::time::Duration::from_secs(
+            config.tls.as_ref().map_or(0, |tls| tls.handshake_timeout),
         );
-        if let Err(error) = future.await {
+
+        let abort_signal = Self::abort_signal();
+        tokio::pin!(abort_signal);
+        loop {
+            let (stream, address) = tokio::select! {
+                result = listener.accept() => match result {
+                    Ok(accepted) => accepted,
+                    Err(error) => {
+                        /* Errors such as running out of file descriptors */
+                        /* persist for a while, so back off before retrying. */
+                        crate::console_warn!("Failed to accept connection: {}", error);
+                        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                        continue;
+                    }
+                },
+                _ = &mut abort_signal => break,
+            };
+            let context = self.context.clone();
+            let acceptor = acceptor.clone();
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                match acceptor {
+                    Some(acceptor) => {
+                        match tokio::time::timeout(handshake_timeout, acceptor.accept(stream)).await
+                        {
+                            Ok(Ok(stream)) => {
+                                let protocol = match stream.get_ref().1.alpn_protocol() {
+                                    Some(b"h2") => Protocol::Http2,
+                                    _ => Protocol::Http1,
+                                };
+                                Self::connection(stream, protocol, address, context, shutdown).await
+                            }
+                            Ok(Err(error)) => {
+                                crate::console_warn!(
+                                    "TLS handshake with {} failed: {}",
+                                    address,
+                                    error
+                                );
+                            }
+                            Err(_elapsed) => {
+                                crate::console_warn!("TLS handshake with {} timed out", address);
+                            }
+                        }
+                    }
+                    None => {
+                        let http2 = &context.config.http2;
+                        let protocol = match http2.enabled && http2.h2c {
+                            true => Protocol::Detect,
+                            false => Protocol::Http1,
+                        };
+                        Self::connection(stream, protocol, address, context, shutdown).await
+                    }
+                }
+            });
+        }
+
+        /* Wait for every open connection to drain. */
+        drop(shutdown);
+        let _ = shutdown_sender.send(());
+        shutdown_sender.closed().await;
+        Ok(())
+    }
+    pub async fn serve(&mut self) {
+        /* Prevent the server from serving twice. */
+        if self.active {
+            return;
+        }
+        self.active = true;
+
+        if let Err(error) = self.listen().await {
             crate::console_error!("Failed to start server: {}", error);
         }
 
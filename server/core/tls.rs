@@ -0,0 +1,165 @@
+use crate::core::{config, error};
+
+type CertifiedKey = std::sync::Arc<tokio_rustls::rustls::sign::CertifiedKey>;
+
+fn certified_key(config: &config::TlsConfig) -> Result<CertifiedKey, error::Error> {
+    use tokio_rustls::rustls;
+
+    let mut reader = std::io::BufReader::new(std::fs::File::open(&config.certificate)?);
+    let certificates = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect::<Vec<_>>();
+    if certificates.is_empty() {
+        let message = format!("No certificates found in {:?}", config.certificate);
+        return Err(error::Error::new_string(message));
+    }
+
+    let mut reader = std::io::BufReader::new(std::fs::File::open(&config.key)?);
+    let private_key = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::RSAKey(key)) => break rustls::PrivateKey(key),
+            Some(rustls_pemfile::Item::PKCS8Key(key)) => break rustls::PrivateKey(key),
+            Some(rustls_pemfile::Item::ECKey(key)) => break rustls::PrivateKey(key),
+            Some(_) => continue,
+            None => {
+                let message = format!("No private key found in {:?}", config.key);
+                return Err(error::Error::new_string(message));
+            }
+        }
+    };
+    let signing_key = match rustls::sign::any_supported_type(&private_key) {
+        Ok(signing_key) => signing_key,
+        Err(_error) => {
+            let message = format!("Unsupported private key type in {:?}", config.key);
+            return Err(error::Error::new_string(message));
+        }
+    };
+    let key = rustls::sign::CertifiedKey::new(certificates, signing_key);
+    Ok(std::sync::Arc::new(key))
+}
+
+/* Serves whichever certificate was loaded last, so it can be swapped live. */
+struct CertificateResolver {
+    key: std::sync::RwLock<CertifiedKey>,
+}
+impl tokio_rustls::rustls::server::ResolvesServerCert for CertificateResolver {
+    fn resolve(
+        &self,
+        _client_hello: tokio_rustls::rustls::server::ClientHello,
+    ) -> Option<CertifiedKey> {
+        match self.key.read() {
+            Ok(key) => Some(key.clone()),
+            Err(_error) => None,
+        }
+    }
+}
+
+fn modified(config: &config::TlsConfig) -> Option<(std::time::SystemTime, std::time::SystemTime)> {
+    let certificate = std::fs::metadata(&config.certificate)
+        .ok()?
+        .modified()
+        .ok()?;
+    let key = std::fs::metadata(&config.key).ok()?.modified().ok()?;
+    Some((certificate, key))
+}
+
+async fn reload(config: config::TlsConfig, resolver: std::sync::Arc<CertificateResolver>) {
+    let period = std::time::Duration::from_secs(config.reload_interval);
+    let mut interval = tokio::time::interval(period);
+    let mut last_modified = modified(&config);
+    loop {
+        interval.tick().await;
+        let current_modified = modified(&config);
+        if current_modified == last_modified {
+            continue;
+        }
+        last_modified = current_modified;
+        match certified_key(&config) {
+            Ok(key) => match resolver.key.write() {
+                Ok(mut guard) => {
+                    *guard = key;
+                    crate::console_log!("Reloaded TLS certificate {:?}", config.certificate);
+                }
+                Err(error) => {
+                    crate::console_warn!("Failed to swap TLS certificate: {}", error);
+                }
+            },
+            Err(error) => {
+                crate::console_warn!("Failed to reload TLS certificate: {}", error);
+            }
+        }
+    }
+}
+
+pub fn acceptor(
+    config: &config::TlsConfig,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> Result<tokio_rustls::TlsAcceptor, error::Error> {
+    crate::console_log!("Loading TLS certificate...");
+
+    let resolver = std::sync::Arc::new(CertificateResolver {
+        key: std::sync::RwLock::new(certified_key(config)?),
+    });
+    if config.reload_interval > 0 {
+        tokio::spawn(reload(config.clone(), resolver.clone()));
+    }
+
+    let mut server_config = tokio_rustls::rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    server_config.alpn_protocols = alpn_protocols;
+    Ok(tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(
+        server_config,
+    )))
+}
+
+pub mod redirect {
+    /* Sends plain HTTP requests to the same host and path over HTTPS. */
+    pub async fn handle(
+        request: hyper::Request<hyper::Body>,
+        port: u16,
+    ) -> Result<hyper::Response<hyper::Body>, std::convert::Infallible> {
+        let host = match request.uri().host() {
+            Some(host) => Some(host),
+            None => match request.headers().get(hyper::header::HOST) {
+                Some(value) => value.to_str().ok(),
+                None => None,
+            },
+        };
+        /* Drop the plain HTTP port but keep bracketed IPv6 literals intact. */
+        let host = host.map(|host| match host.rsplit_once(':') {
+            Some((hostname, _port)) if !host.ends_with(']') => hostname,
+            _ => host,
+        });
+        let path = match request.uri().path_and_query() {
+            Some(path) => path.as_str(),
+            None => "/",
+        };
+        let mut response = hyper::Response::new(hyper::Body::empty());
+        match host {
+            Some(host) => {
+                let location = match port {
+                    443 => format!("https://{}{}", host, path),
+                    _ => format!("https://{}:{}{}", host, port, path),
+                };
+                match hyper::header::HeaderValue::from_str(location.as_str()) {
+                    Ok(value) => {
+                        *response.status_mut() = hyper::StatusCode::PERMANENT_REDIRECT;
+                        response
+                            .headers_mut()
+                            .insert(hyper::header::LOCATION, value);
+                    }
+                    Err(_error) => {
+                        *response.status_mut() = hyper::StatusCode::BAD_REQUEST;
+                    }
+                }
+            }
+            None => {
+                *response.status_mut() = hyper::StatusCode::BAD_REQUEST;
+            }
+        }
+        Ok(response)
+    }
+}
@@ -0,0 +1,157 @@
+use crate::core::{config, error, util};
+
+use hmac::Mac;
+use sha2::Digest;
+
+const ALPHABET: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+const DIGITS: u32 = 6;
+const PERIOD: u64 = 30;
+
+/* A new random secret, base32 encoded the way authenticator apps expect. */
+pub fn secret() -> String {
+    let mut bytes = [0u8; 20];
+    rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut bytes);
+    base32::encode(ALPHABET, &bytes)
+}
+
+/* Key URI for QR codes, see github.com/google/google-authenticator/wiki. */
+pub fn uri(config: &config::TotpConfig, account: &str, secret: &str) -> String {
+    use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+    let issuer = utf8_percent_encode(config.issuer.as_str(), NON_ALPHANUMERIC).to_string();
+    let account = utf8_percent_encode(account, NON_ALPHANUMERIC).to_string();
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer, account, secret, issuer, DIGITS, PERIOD
+    )
+}
+
+/* RFC 6238 code for one time step, HMAC-SHA1 with dynamic truncation. */
+fn code(key: &[u8], step: u64) -> Result<u32, error::Error> {
+    let mut mac = match hmac::Hmac::<sha1::Sha1>::new_from_slice(key) {
+        Ok(mac) => mac,
+        Err(_error) => return Err(error::Error::new_str("Invalid TOTP key length")),
+    };
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let mut truncated = [0u8; 4];
+    truncated.copy_from_slice(&hash[offset..offset + 4]);
+    Ok((u32::from_be_bytes(truncated) & 0x7fff_ffff) % 10u32.pow(DIGITS))
+}
+
+/* Returns the matching time step, which callers keep to reject replays of */
+/* it and of every earlier step. */
+pub fn verify(
+    config: &config::TotpConfig,
+    secret: &str,
+    code_input: &str,
+    last_step: u64,
+) -> Result<Option<u64>, error::Error> {
+    let key = match base32::decode(ALPHABET, secret) {
+        Some(key) => key,
+        None => return Err(error::Error::new_str("Invalid TOTP secret")),
+    };
+    let code_input = code_input.trim();
+    if code_input.len() != DIGITS as usize || !code_input.bytes().all(|b| b.is_ascii_digit()) {
+        return Ok(None);
+    }
+    let code_input = code_input.parse::<u32>()?;
+    let current = util::now()? / PERIOD;
+    for step in current.saturating_sub(config.skew)..=current + config.skew {
+        if step > last_step && code(&key, step)? == code_input {
+            return Ok(Some(step));
+        }
+    }
+    Ok(None)
+}
+
+/* Recovery codes have 80 random bits, so a plain digest is enough at rest. */
+pub fn recovery_hash(code: &str) -> String {
+    let normalized = code.trim().replace('-', "").to_lowercase();
+    format!("{:x}", sha2::Sha256::digest(normalized.as_bytes()))
+}
+
+/* Returns the codes to show once, and their hashes to store. */
+pub fn recovery_codes(count: usize) -> (Vec<String>, Vec<String>) {
+    let codes = (0..count)
+        .map(|_| {
+            let mut bytes = [0u8; 10];
+            rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut bytes);
+            let encoded = base32::encode(ALPHABET, &bytes).to_lowercase();
+            let groups = encoded
+                .as_bytes()
+                .chunks(4)
+                .map(|chunk| String::from_utf8_lossy(chunk).to_string())
+                .collect::<Vec<_>>();
+            groups.join("-")
+        })
+        .collect::<Vec<_>>();
+    let hashes = codes.iter().map(|code| recovery_hash(code)).collect();
+    (codes, hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RFC_KEY: &[u8] = b"12345678901234567890";
+
+    /* RFC 6238 appendix B SHA1 values, reduced from eight digits to six. */
+    #[test]
+    fn matches_rfc_6238_vectors() {
+        for (time, expected) in [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+            (20000000000, 353130),
+        ] {
+            assert_eq!(code(RFC_KEY, time / PERIOD).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn verifies_within_skew_after_last_step() {
+        let config = config::TotpConfig::default();
+        let secret = base32::encode(ALPHABET, RFC_KEY);
+        let current = util::now().unwrap() / PERIOD;
+        let input = |step: u64| format!("{:06}", code(RFC_KEY, step).unwrap());
+
+        assert_eq!(
+            verify(&config, &secret, &input(current), 0).unwrap(),
+            Some(current)
+        );
+        assert_eq!(
+            verify(&config, &secret, &input(current + 1), 0).unwrap(),
+            Some(current + 1)
+        );
+        assert_eq!(
+            verify(&config, &secret, &input(current + 3), 0).unwrap(),
+            None
+        );
+        assert_eq!(
+            verify(&config, &secret, &input(current - 3), 0).unwrap(),
+            None
+        );
+        /* A step already used, or one before it, is a replay. */
+        assert_eq!(
+            verify(&config, &secret, &input(current), current).unwrap(),
+            None
+        );
+        assert_eq!(
+            verify(&config, &secret, &input(current - 1), current).unwrap(),
+            None
+        );
+        assert_eq!(verify(&config, &secret, "12345", 0).unwrap(), None);
+        assert_eq!(verify(&config, &secret, "12a456", 0).unwrap(), None);
+    }
+
+    #[test]
+    fn normalizes_recovery_codes() {
+        assert_eq!(recovery_hash(" ABCD-efgh\n"), recovery_hash("abcdefgh"));
+        let (codes, hashes) = recovery_codes(2);
+        assert_eq!(recovery_hash(&codes[0].to_uppercase()), hashes[0]);
+        assert_ne!(hashes[0], hashes[1]);
+    }
+}
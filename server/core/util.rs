@@ -1,3 +1,9 @@
+use crate::core::error;
+
+pub fn now() -> Result<u64, error::Error> {
+    let duration = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+    Ok(duration.as_secs())
+}
 pub fn uuid() -> String {
     uuid::Uuid::new_v4().to_string()
 }
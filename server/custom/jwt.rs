@@ -1,8 +1,77 @@
-use crate::core::auth;
+use crate::core::{auth, config};
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize, juniper::GraphQLEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Admin,
+}
+impl Role {
+    pub fn permissions(&self) -> &'static [Permission] {
+        match self {
+            Role::User => &[],
+            Role::Admin => &[Permission::ReadUsers, Permission::ManageUsers],
+        }
+    }
+}
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize, juniper::GraphQLEnum,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /* Read any account, not only one's own. */
+    ReadUsers,
+    /* Change roles, revoke sessions and unlock any account. */
+    ManageUsers,
+}
+
+/* Accounts listed in the configuration are administrators whatever roles */
+/* they have stored, so a fresh deployment can appoint the first one. Only */
+/* once the address is verified, anyone can sign up with an unproven one. */
+pub fn roles(
+    config: &config::AuthConfig,
+    email: &str,
+    email_verified: bool,
+    roles: &[Role],
+) -> Vec<Role> {
+    let mut roles = roles.to_vec();
+    let admin = email_verified
+        && config
+            .admins
+            .iter()
+            .any(|admin| admin.eq_ignore_ascii_case(email));
+    if admin && !roles.contains(&Role::Admin) {
+        roles.push(Role::Admin);
+    }
+    roles
+}
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct AdditionalData {
     pub email: String,
+    #[serde(default)]
+    pub email_verified: bool,
+    #[serde(default)]
+    pub roles: Vec<Role>,
+    /* Set for API keys, which only get the permissions listed. */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<Permission>>,
+}
+impl AdditionalData {
+    pub fn permits(&self, permission: Permission) -> bool {
+        let scoped = match &self.scopes {
+            Some(scopes) => scopes.contains(&permission),
+            None => true,
+        };
+        scoped
+            && self
+                .roles
+                .iter()
+                .any(|role| role.permissions().contains(&permission))
+    }
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
@@ -10,14 +79,21 @@ pub struct Payload {
     pub id: juniper::ID,
     pub jti: Option<String>,
     pub email: String,
+    #[serde(default)]
+    pub email_verified: bool,
+    #[serde(default)]
+    pub roles: Vec<Role>,
 }
 
-pub fn convert(payload: &Payload, exp: usize) -> auth::Claims {
+pub fn convert(payload: &Payload, registered: auth::Registered) -> auth::Claims {
     auth::Claims {
         sub: payload.id.to_string(),
-        exp,
+        registered,
         ajd: AdditionalData {
             email: payload.email.clone(),
+            email_verified: payload.email_verified,
+            roles: payload.roles.clone(),
+            scopes: None,
         },
         jti: payload.jti.clone(),
     }
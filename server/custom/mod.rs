@@ -1,3 +1,4 @@
 pub mod jwt;
 pub mod redis;
+pub mod routes;
 pub mod schema;
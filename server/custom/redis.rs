@@ -14,7 +14,7 @@ pub trait RedisIndex {
         let schema_fields = match Self::tag() {
             Some((tag_name, tag_as)) => {
                 let schema_field = redis::FTSchemaField::build()
-                    .name(tag_name.into())
+                    .name(tag_name)
                     .field_type("TAG".into())
                     .field_as(tag_as);
                 vec![schema_field]
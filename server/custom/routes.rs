@@ -0,0 +1,155 @@
+use crate::core::{auth, context, error, message, middleware, oidc, router};
+use crate::custom::schema;
+
+/* Register application-specific routes here, e.g.
+router.get("/users/:id", |message, context| Box::pin(users::get(message, context)))?; */
+pub fn register(router: &mut router::Router) -> Result<(), error::Error> {
+    router
+        .group("/auth/oidc")
+        .layer(middleware::rate_limit::RateLimit::new("login"))
+        .get("/:provider", |message, context| {
+            Box::pin(sign_in::authorize(message, context))
+        })?
+        .get("/:provider/callback", |message, context| {
+            Box::pin(sign_in::callback(message, context))
+        })?;
+    Ok(())
+}
+
+/* Sign in with OpenID Connect providers, using the authorization code flow */
+/* with PKCE. The state is also kept in a cookie, so a callback only */
+/* completes in the browser that started it. */
+pub mod sign_in {
+    use super::*;
+    use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+    /* Everything but RFC 3986 unreserved characters. */
+    const FRAGMENT: &AsciiSet = &NON_ALPHANUMERIC
+        .remove(b'-')
+        .remove(b'.')
+        .remove(b'_')
+        .remove(b'~');
+
+    fn state_cookie(state: &str) -> cookie::Cookie<'static> {
+        /* Lax, the callback is a cross-site navigation from the provider. */
+        cookie::Cookie::build("oidc_state", state.to_string())
+            .http_only(true)
+            .path("/auth/oidc")
+            .secure(true)
+            .same_site(cookie::SameSite::Lax)
+            .finish()
+    }
+    fn redirect(message: &mut message::Message, location: &str) -> Result<(), error::Error> {
+        let location = hyper::header::HeaderValue::from_str(location)?;
+        message
+            .response
+            .headers_mut()
+            .insert(hyper::header::LOCATION, location);
+        *message.response.status_mut() = hyper::StatusCode::SEE_OTHER;
+        Ok(())
+    }
+    /* Back to the application, with the outcome in the fragment. */
+    fn finish(
+        message: &mut message::Message,
+        context: &context::Context,
+        key: &str,
+        value: &str,
+    ) -> Result<(), error::Error> {
+        let location = format!(
+            "{}#{}={}",
+            context.config.auth.oidc.redirect,
+            key,
+            utf8_percent_encode(value, FRAGMENT)
+        );
+        redirect(message, location.as_str())
+    }
+    fn provider(message: &mut message::Message, context: &context::Context) -> Option<String> {
+        let provider = message.parameters.get("provider").cloned();
+        match provider {
+            Some(provider) if context.config.auth.oidc.providers.contains_key(&provider) => {
+                Some(provider)
+            }
+            _ => {
+                *message.response.status_mut() = hyper::StatusCode::NOT_FOUND;
+                None
+            }
+        }
+    }
+
+    pub async fn authorize(
+        message: &mut message::Message,
+        context: context::Context,
+    ) -> Result<(), error::Error> {
+        let provider = match provider(message, &context) {
+            Some(provider) => provider,
+            None => return Ok(()),
+        };
+        let issuer = auth::util::issuer(message, &context);
+        let redirect_uri = oidc::redirect_uri(issuer.as_str(), provider.as_str());
+        let (location, state) = context
+            .oidc
+            .authorize(&context, provider.as_str(), redirect_uri.as_str())
+            .await?;
+        message.cookies.add(state_cookie(state.as_str()));
+        redirect(message, location.as_str())
+    }
+    pub async fn callback(
+        message: &mut message::Message,
+        context: context::Context,
+    ) -> Result<(), error::Error> {
+        let provider = match provider(message, &context) {
+            Some(provider) => provider,
+            None => return Ok(()),
+        };
+        let query = message.request.uri().query().unwrap_or_default();
+        let query = serde_urlencoded::from_str::<std::collections::HashMap<String, String>>(query)?;
+        let cookie = message
+            .cookies
+            .get("oidc_state")
+            .map(|cookie| cookie.value().to_string());
+        message.cookies.remove(state_cookie(""));
+
+        /* Errors from the provider, e.g. access_denied, are passed on. */
+        if let Some(error) = query.get("error") {
+            return finish(message, &context, "error", error.as_str());
+        }
+        let (code, state) = match (query.get("code"), query.get("state")) {
+            (Some(code), Some(state)) if cookie.as_ref() == Some(state) => (code, state),
+            _ => return finish(message, &context, "error", "Invalid sign-in state"),
+        };
+        let issuer = auth::util::issuer(message, &context);
+        let redirect_uri = oidc::redirect_uri(issuer.as_str(), provider.as_str());
+        let identity = context
+            .oidc
+            .callback(
+                &context,
+                provider.as_str(),
+                redirect_uri.as_str(),
+                code.as_str(),
+                state.as_str(),
+            )
+            .await;
+        let result = match identity {
+            Ok(identity) => schema::login_external(&context, message, &identity).await,
+            Err(error) => Err(error),
+        };
+        match result {
+            Ok(schema::ExternalLogin::Token(token)) => {
+                finish(message, &context, "token", token.as_str())
+            }
+            Ok(schema::ExternalLogin::Challenge(challenge)) => {
+                finish(message, &context, "challenge", challenge.as_str())
+            }
+            /* The reason may tell whether the email has an account, so it */
+            /* stays in the log. */
+            Err(error) => {
+                crate::console_warn!("Failed to sign in with {}: {}", provider, error);
+                let reason = format!(
+                    "Could not sign in with {}, if you have an account log in with its password",
+                    provider
+                );
+                finish(message, &context, "error", reason.as_str())
+            }
+        }
+    }
+}
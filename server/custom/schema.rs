@@ -1,15 +1,19 @@
-use crate::core::{auth, error, graphql, util};
+use crate::core::{auth, config, context, error, graphql, mail, message, oidc, totp, util};
 use crate::custom::{jwt, redis};
 
 use self::redis::RedisIndex;
+use ::redis::AsyncCommands;
+use auth::guard::{Authenticated, Email, Guard, HasPermission, Interactive, Subject};
 use auth::Token;
+use jwt::{Permission, Role};
 
+#[allow(dead_code)]
 #[derive(juniper::GraphQLObject)]
 pub struct Error {
     message: String,
 }
 
-#[juniper::graphql_interface(for = [User])]
+#[juniper::graphql_interface(for = [User], context = graphql::JuniperContext)]
 pub trait Node {
     fn id(&self) -> juniper::ID;
 }
@@ -19,11 +23,105 @@ pub struct User {
     id: juniper::ID,
     email: String,
     password: String,
+    #[serde(default)]
+    email_verified: bool,
+    #[serde(default)]
+    two_factor: Option<TwoFactor>,
+    #[serde(default)]
+    roles: Vec<Role>,
+    #[serde(default)]
+    identities: Vec<LinkedIdentity>,
 
-    pub jti: Option<String>,
     pub sub: String,
 }
-#[juniper::graphql_object(impl = NodeValue)]
+
+/* An account at an identity provider the user can sign in with. */
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct LinkedIdentity {
+    provider: String,
+    subject: String,
+}
+impl LinkedIdentity {
+    fn key(&self) -> String {
+        format!("oidc:identities:{}:{}", self.provider, self.subject)
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct TwoFactor {
+    secret: String,
+    /* SHA-256 hashes of the unused recovery codes. */
+    recovery_codes: Vec<String>,
+    /* Latest accepted time step, codes up to it cannot be used again. */
+    last_step: u64,
+}
+
+#[derive(juniper::GraphQLObject)]
+pub struct TotpEnrollment {
+    secret: String,
+    uri: String,
+}
+
+fn timestamp(seconds: u64) -> chrono::DateTime<chrono::Utc> {
+    use chrono::TimeZone;
+    chrono::Utc.timestamp_opt(seconds as i64, 0).unwrap()
+}
+
+/* A signed in device, one per refresh token family. */
+#[derive(juniper::GraphQLObject)]
+pub struct Session {
+    id: juniper::ID,
+    user_agent: String,
+    address: String,
+    created: chrono::DateTime<chrono::Utc>,
+    last_used: chrono::DateTime<chrono::Utc>,
+    /* Whether this is the session making the request. */
+    current: bool,
+}
+impl Session {
+    fn new(session: auth::revocation::Session, current: Option<&str>) -> Self {
+        Self {
+            current: current == Some(session.id.as_str()),
+            id: session.id.into(),
+            user_agent: session.user_agent,
+            address: session.address,
+            created: timestamp(session.created),
+            last_used: timestamp(session.last_used),
+        }
+    }
+}
+
+#[derive(juniper::GraphQLObject)]
+pub struct ApiKey {
+    id: juniper::ID,
+    name: String,
+    scopes: Vec<Permission>,
+    created: chrono::DateTime<chrono::Utc>,
+    expires: Option<chrono::DateTime<chrono::Utc>>,
+    last_used: Option<chrono::DateTime<chrono::Utc>>,
+}
+impl From<auth::api_key::ApiKey> for ApiKey {
+    fn from(api_key: auth::api_key::ApiKey) -> Self {
+        Self {
+            id: api_key.id.into(),
+            name: api_key.name,
+            scopes: api_key.scopes,
+            created: timestamp(api_key.created),
+            expires: api_key.expires.map(timestamp),
+            last_used: api_key.last_used.map(timestamp),
+        }
+    }
+}
+
+#[derive(juniper::GraphQLObject)]
+pub struct CreatedApiKey {
+    /* The only time the key itself is shown. */
+    key: String,
+    api_key: ApiKey,
+}
+
+const RECOVERY_CODES: usize = 10;
+#[juniper::graphql_object(impl = NodeValue, context = graphql::JuniperContext)]
 impl User {
     fn id(&self) -> juniper::ID {
         self.id.clone()
@@ -31,6 +129,177 @@ impl User {
     fn email(&self) -> String {
         self.email.clone()
     }
+    fn email_verified(&self) -> bool {
+        self.email_verified
+    }
+    fn two_factor_enabled(&self) -> bool {
+        self.two_factor.is_some()
+    }
+    fn roles(&self) -> Vec<Role> {
+        self.roles.clone()
+    }
+    async fn sessions(
+        &self,
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<Vec<Session>> {
+        context
+            .authorize(Subject(self.sub.as_str()).or(HasPermission(Permission::ReadUsers)))
+            .await?;
+        let current = {
+            let message = context.message.try_read()?;
+            auth::revocation::current(&message, &context.global)
+        };
+        let sessions = auth::revocation::sessions(&context.global, self.sub.as_str()).await?;
+        let sessions = sessions
+            .into_iter()
+            .map(|session| Session::new(session, current.as_deref()))
+            .collect();
+        Ok(sessions)
+    }
+    async fn api_keys(
+        &self,
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<Vec<ApiKey>> {
+        context
+            .authorize(Subject(self.sub.as_str()).or(HasPermission(Permission::ReadUsers)))
+            .await?;
+        let api_keys = auth::api_key::list(&context.global, self.sub.as_str()).await?;
+        Ok(api_keys.into_iter().map(ApiKey::from).collect())
+    }
+}
+impl User {
+    async fn find(context: &context::Context, sub: &str) -> Result<User, error::Error> {
+        let mut redis_json = context.redis.json().await?;
+        let json_result = redis_json.get(sub.to_string(), None, None).await?;
+        Ok(serde_json::from_str::<User>(json_result.as_str())?)
+    }
+    async fn find_by_email(
+        context: &context::Context,
+        email: &str,
+    ) -> Result<Option<User>, error::Error> {
+        let query = format!(
+            "@email:{{{}}}",
+            email.replace("@", "\\@").replace(".", "\\.")
+        );
+        let mut redis_search = context.redis.search().await?;
+        let search_result = redis_search.search(User::index_name(), query, None).await?;
+        if search_result.results.len() > 1 {
+            let message = format!("More than one user found with email {}", email);
+            return Err(error::Error::new_string(message));
+        }
+        match search_result.results.first() {
+            Some(result) => Ok(Some(serde_json::from_str::<User>(result.value.as_str())?)),
+            None => Ok(None),
+        }
+    }
+    async fn set_password(
+        context: &context::Context,
+        sub: &str,
+        password: &str,
+    ) -> Result<(), error::Error> {
+        let hashed_password = auth::password::hash(&context.config.auth.password, password)?;
+        let mut redis_json = context.redis.json().await?;
+        redis_json
+            .set(
+                sub.to_string(),
+                "$.password".into(),
+                serde_json::to_string(&hashed_password)?,
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+    /* The user linked to the identity, else the account with its verified */
+    /* email, else a new account without a usable password. */
+    async fn from_identity(
+        context: &context::Context,
+        identity: &oidc::Identity,
+    ) -> Result<User, error::Error> {
+        let linked = LinkedIdentity {
+            provider: identity.provider.clone(),
+            subject: identity.subject.clone(),
+        };
+        let mut redis_main = context.redis.main().await?;
+        if let Some(sub) = redis_main.get::<_, Option<String>>(linked.key()).await? {
+            match User::find(context, sub.as_str()).await {
+                Ok(user) => return Ok(user),
+                /* The account was deleted since. */
+                Err(_error) => redis_main.del::<_, ()>(linked.key()).await?,
+            }
+        }
+        let email = match &identity.email {
+            Some(email) => email.clone(),
+            None => {
+                let message = format!("{} did not share an email address", identity.provider);
+                return Err(error::Error::new_string(message));
+            }
+        };
+        let user = match User::find_by_email(context, email.as_str()).await? {
+            /* Both sides must have verified the address, or whoever claimed */
+            /* it first could take over the other account. */
+            Some(user) if user.email_verified && identity.email_verified => {
+                let mut identities = user.identities.clone();
+                identities.push(linked.clone());
+                let mut redis_json = context.redis.json().await?;
+                redis_json
+                    .set(
+                        user.sub.clone(),
+                        "$.identities".into(),
+                        serde_json::to_string(&identities)?,
+                        None,
+                    )
+                    .await?;
+                User { identities, ..user }
+            }
+            Some(_user) => {
+                let message = format!(
+                    "Email {} is already in use, log in with its password and verify it first",
+                    email
+                );
+                return Err(error::Error::new_string(message));
+            }
+            None => {
+                let id = format!("{}{}", User::prefix(), util::uuid());
+                let password = auth::password::hash(&context.config.auth.password, &util::uuid())?;
+                let user = User {
+                    id: id.clone().into(),
+                    email,
+                    password,
+                    email_verified: identity.email_verified,
+                    two_factor: None,
+                    roles: vec![Role::User],
+                    identities: vec![linked.clone()],
+
+                    sub: id.clone(),
+                };
+                let mut redis_json = context.redis.json().await?;
+                redis_json
+                    .set(id, "$".into(), serde_json::to_string(&user)?, None)
+                    .await?;
+                user
+            }
+        };
+        redis_main
+            .set::<_, _, ()>(linked.key(), user.sub.as_str())
+            .await?;
+        Ok(user)
+    }
+    async fn set_two_factor(
+        context: &context::Context,
+        sub: &str,
+        two_factor: Option<&TwoFactor>,
+    ) -> Result<(), error::Error> {
+        let mut redis_json = context.redis.json().await?;
+        redis_json
+            .set(
+                sub.to_string(),
+                "$.two_factor".into(),
+                serde_json::to_string(&two_factor)?,
+                None,
+            )
+            .await?;
+        Ok(())
+    }
 }
 #[juniper::graphql_interface]
 impl Node for User {
@@ -48,13 +317,16 @@ impl Query {
     ) -> juniper::FieldResult<Option<NodeValue>> {
         let regex = regex::Regex::new("(.*:)*.*")?;
         let id = id.to_string();
-        fn prefix(id: &String, regex: &regex::Regex) -> Option<String> {
-            let captures = regex.captures(id.as_str())?;
+        fn prefix(id: &str, regex: &regex::Regex) -> Option<String> {
+            let captures = regex.captures(id)?;
             let prefix = captures.get(1)?;
             Some(prefix.as_str().to_string())
         }
         if let Some(prefix) = prefix(&id, &regex) {
             if prefix == User::prefix() {
+                context
+                    .authorize(Subject(id.as_str()).or(HasPermission(Permission::ReadUsers)))
+                    .await?;
                 let mut redis_json = context.global.redis.json().await?;
                 let json_data = match redis_json.get(id.clone(), None, None).await {
                     Ok(data) => Ok(data),
@@ -74,29 +346,11 @@ impl Query {
         email: String,
         context: &graphql::JuniperContext,
     ) -> juniper::FieldResult<Option<User>> {
-        {
-            let message = context.message.try_read()?;
-            let claims = auth::util::authenticate(&message, &context.global)?;
-            if claims.ajd.email != email {
-                let message = format!("Failed to authenticate user with email {}", email);
-                return Err(error::Error::new_string(message).into());
-            }
-        }
-        let query = format!(
-            "@email:{{{}}}",
-            email.replace("@", "\\@").replace(".", "\\.")
-        );
-        let mut redis_search = context.global.redis.search().await?;
-        let search_result = redis_search.search(User::index_name(), query, None).await?;
-        if search_result.results.len() > 1 {
-            let message = format!("More than one user found with email {}", email);
-            return Err(error::Error::new_string(message).into());
-        }
-        match search_result.results.first() {
-            Some(result) => {
-                let user = serde_json::from_str::<User>(result.value.as_str())?;
-                Ok(Some(user))
-            }
+        context
+            .authorize(Email(email.as_str()).or(HasPermission(Permission::ReadUsers)))
+            .await?;
+        match User::find_by_email(&context.global, email.as_str()).await? {
+            Some(user) => Ok(Some(user)),
             None => {
                 let message = format!("No user found with email {}", email);
                 Err(error::Error::new_string(message).into())
@@ -106,14 +360,7 @@ impl Query {
     pub async fn read_current_user(
         context: &graphql::JuniperContext,
     ) -> juniper::FieldResult<Option<User>> {
-        let claims = {
-            let message = context.message.try_read()?;
-            auth::util::authenticate(&message, &context.global)?
-        };
-        let mut redis_json = context.global.redis.json().await?;
-        let json_result = redis_json.get(claims.sub, None, None).await?;
-        let user = serde_json::from_str::<User>(json_result.as_str())?;
-        Ok(Some(user))
+        Ok(Some(current_user(context, Authenticated).await?))
     }
 }
 impl Query {
@@ -121,38 +368,364 @@ impl Query {
         Self {}
     }
 }
+/* Applies a rate limit policy to the client address of a resolver. */
+async fn rate_limit(context: &graphql::JuniperContext, policy: &str) -> Result<(), error::Error> {
+    let identity = {
+        let message = context.message.try_read()?;
+        format!("ip:{}", message.address.ip())
+    };
+    if let Some(rate_limit) =
+        auth::util::rate_limit(&context.global, policy, identity.as_str()).await
+    {
+        let mut message = context.message.try_write()?;
+        rate_limit.headers(&mut message.response);
+        if !rate_limit.allowed {
+            return Err(error::Error::new_str("Too many attempts, try again later"));
+        }
+    }
+    Ok(())
+}
+
+async fn current_user<G: Guard>(
+    context: &graphql::JuniperContext,
+    guard: G,
+) -> Result<User, error::Error> {
+    let claims = context.authorize(guard).await?;
+    User::find(&context.global, claims.sub.as_str()).await
+}
+
+/* Sensitive changes ask for the password again, a stolen access token */
+/* alone is not enough. */
+async fn confirm_password(
+    context: &graphql::JuniperContext,
+    user: &User,
+    password: &str,
+) -> Result<(), error::Error> {
+    rate_limit(context, "login").await?;
+    match auth::password::verify(password, user.password.as_str())? {
+        true => Ok(()),
+        false => Err(error::Error::new_str("Incorrect password")),
+    }
+}
+
+/* Rejects logins for locked accounts and addresses, returning the address. */
+async fn locked_out(
+    context: &graphql::JuniperContext,
+    email: &str,
+) -> Result<std::net::IpAddr, error::Error> {
+    let address = {
+        let message = context.message.try_read()?;
+        message.address.ip()
+    };
+    if let Some(retry_after) = auth::lockout::check(&context.global, email, address).await? {
+        let mut message = context.message.try_write()?;
+        message
+            .response
+            .headers_mut()
+            .insert(hyper::header::RETRY_AFTER, retry_after.into());
+        return Err(error::Error::new_str(
+            "Too many failed logins, try again later",
+        ));
+    }
+    Ok(address)
+}
+
+/* Both scripts compare against the stored document and update it in one */
+/* step, so concurrent logins cannot spend the same step or recovery code. */
+const TOTP_STEP_SCRIPT: &str = r"
+    local stored = redis.call('JSON.GET', KEYS[1], '$.two_factor')
+    if not stored then
+        return 0
+    end
+    local two_factor = cjson.decode(stored)[1]
+    if type(two_factor) ~= 'table' or two_factor.secret ~= ARGV[1] then
+        return 0
+    end
+    if two_factor.last_step >= tonumber(ARGV[2]) then
+        return 0
+    end
+    redis.call('JSON.SET', KEYS[1], '$.two_factor.last_step', ARGV[2])
+    return 1
+";
+const RECOVERY_CODE_SCRIPT: &str = r"
+    local stored = redis.call('JSON.GET', KEYS[1], '$.two_factor.recovery_codes')
+    if not stored then
+        return 0
+    end
+    local codes = cjson.decode(stored)[1]
+    if type(codes) ~= 'table' then
+        return 0
+    end
+    for index, code in ipairs(codes) do
+        if code == ARGV[1] then
+            redis.call('JSON.ARRPOP', KEYS[1], '$.two_factor.recovery_codes', index - 1)
+            return 1
+        end
+    end
+    return 0
+";
+
+/* Accepts a current TOTP code or spends one of the recovery codes. */
+async fn check_two_factor(
+    context: &context::Context,
+    user: &User,
+    code: &str,
+) -> Result<bool, error::Error> {
+    let two_factor = match &user.two_factor {
+        Some(two_factor) => two_factor,
+        None => return Ok(false),
+    };
+    let config = &context.config.auth.totp;
+    let mut redis_main = context.redis.main().await?;
+    match totp::verify(
+        config,
+        two_factor.secret.as_str(),
+        code,
+        two_factor.last_step,
+    )? {
+        Some(step) => Ok(::redis::Script::new(TOTP_STEP_SCRIPT)
+            .key(user.sub.as_str())
+            .arg(two_factor.secret.as_str())
+            .arg(step)
+            .invoke_async::<_, bool>(&mut redis_main)
+            .await?),
+        None => {
+            let hash = totp::recovery_hash(code);
+            if !two_factor.recovery_codes.contains(&hash) {
+                return Ok(false);
+            }
+            Ok(::redis::Script::new(RECOVERY_CODE_SCRIPT)
+                .key(user.sub.as_str())
+                .arg(hash)
+                .invoke_async::<_, bool>(&mut redis_main)
+                .await?)
+        }
+    }
+}
+
+/* Every login starts a new refresh token family, except for limited */
+/* accounts, which get no refresh token and so no lasting session. */
+async fn start_session(
+    context: &context::Context,
+    user: &User,
+    user_agent: &str,
+    address: std::net::IpAddr,
+) -> Result<Option<String>, error::Error> {
+    let verification = context.config.auth.verification;
+    if !user.email_verified && verification != config::Verification::Optional {
+        return Ok(None);
+    }
+    let jti = auth::revocation::start(context, user.sub.as_str(), user_agent, address).await?;
+    Ok(Some(jti))
+}
+/* Sets the refresh cookie when there is a session, returns the access token. */
+fn create_tokens(
+    context: &context::Context,
+    user: User,
+    jti: Option<String>,
+    message: &mut message::Message,
+) -> Result<String, error::Error> {
+    let roles = jwt::roles(
+        &context.config.auth,
+        &user.email,
+        user.email_verified,
+        &user.roles,
+    );
+    let claims = jwt::Payload {
+        id: user.id,
+        jti,
+        email: user.email,
+        email_verified: user.email_verified,
+        roles,
+    };
+    let issuer = auth::util::issuer(message, context);
+    if claims.jti.is_some() {
+        context
+            .auth
+            .refresh
+            .create(claims.clone(), issuer.as_str(), message)?;
+    }
+    context.auth.access.create(claims, issuer.as_str(), message)
+}
+async fn issue_tokens(
+    context: &graphql::JuniperContext,
+    user: User,
+) -> Result<String, error::Error> {
+    let (user_agent, address) = {
+        let message = context.message.try_read()?;
+        let user_agent = auth::revocation::user_agent(&message);
+        (user_agent, message.address.ip())
+    };
+    let jti = start_session(&context.global, &user, &user_agent, address).await?;
+    let mut message = context.message.try_write()?;
+    create_tokens(&context.global, user, jti, &mut message)
+}
+
+/* Outcome of signing in with an identity provider. */
+pub enum ExternalLogin {
+    Token(String),
+    /* Two-factor authentication is on, finish with loginUserTwoFactor. */
+    Challenge(String),
+}
+pub async fn login_external(
+    context: &context::Context,
+    message: &mut message::Message,
+    identity: &oidc::Identity,
+) -> Result<ExternalLogin, error::Error> {
+    let user = User::from_identity(context, identity).await?;
+    let verification = context.config.auth.verification;
+    if !user.email_verified && verification == config::Verification::Required {
+        let message = format!("Email {} is not verified", user.email);
+        return Err(error::Error::new_string(message));
+    }
+    if user.two_factor.is_some() {
+        let lifetime = context.config.auth.totp.challenge_lifetime;
+        let challenge =
+            auth::ticket::issue(context, "challenge", user.sub.as_str(), lifetime).await?;
+        return Ok(ExternalLogin::Challenge(challenge));
+    }
+    let user_agent = auth::revocation::user_agent(message);
+    let jti = start_session(context, &user, &user_agent, message.address.ip()).await?;
+    let token = create_tokens(context, user, jti, message)?;
+    Ok(ExternalLogin::Token(token))
+}
+
+/* Verification and reset tickets name the address they were mailed to, */
+/* so changing the email voids every link sent before. */
+#[derive(serde::Serialize, serde::Deserialize)]
+struct MailedTicket {
+    sub: String,
+    email: String,
+    /* The address an email change was mailed to, set once it is followed. */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    new_email: Option<String>,
+}
+async fn issue_mailed_ticket(
+    context: &context::Context,
+    purpose: &str,
+    user: &User,
+    new_email: Option<&str>,
+    lifetime: usize,
+) -> Result<String, error::Error> {
+    let ticket = MailedTicket {
+        sub: user.sub.clone(),
+        email: user.email.clone(),
+        new_email: new_email.map(str::to_string),
+    };
+    let value = serde_json::to_string(&ticket)?;
+    auth::ticket::issue(context, purpose, value.as_str(), lifetime).await
+}
+/* The user the ticket was mailed to, if it still has that address. */
+async fn redeem_mailed_ticket(
+    context: &context::Context,
+    purpose: &str,
+    token: &str,
+) -> Result<Option<(User, Option<String>)>, error::Error> {
+    let ticket = match auth::ticket::redeem(context, purpose, token).await? {
+        Some(value) => match serde_json::from_str::<MailedTicket>(value.as_str()) {
+            Ok(ticket) => ticket,
+            Err(_error) => return Ok(None),
+        },
+        None => return Ok(None),
+    };
+    let user = User::find(context, ticket.sub.as_str()).await?;
+    match user.email == ticket.email {
+        true => Ok(Some((user, ticket.new_email))),
+        false => Ok(None),
+    }
+}
+
+/* Mails a single-use link that proves the user owns their email address. */
+async fn send_verification(
+    context: &graphql::JuniperContext,
+    user: &User,
+) -> Result<(), error::Error> {
+    let public_url = auth::util::public_url(&context.global)?;
+    let lifetime = context.global.config.auth.verify_lifetime;
+    let token = issue_mailed_ticket(&context.global, "verify", user, None, lifetime).await?;
+    let link = format!("{}/verify-email?token={}", public_url, token);
+    let email = mail::Email {
+        to: user.email.clone(),
+        subject: "Verify your email address".to_string(),
+        body: format!(
+            "Follow this link within {} hours to verify your email address:\n\n{}\n\n\
+             If you did not create an account, ignore this email.",
+            lifetime / 3600,
+            link
+        ),
+    };
+    context.global.mail.send(email).await
+}
+/* Mails the new address a link that moves the account over to it. */
+async fn send_email_change(
+    context: &graphql::JuniperContext,
+    user: &User,
+    new_email: &str,
+) -> Result<(), error::Error> {
+    let public_url = auth::util::public_url(&context.global)?;
+    let lifetime = context.global.config.auth.verify_lifetime;
+    let token =
+        issue_mailed_ticket(&context.global, "change", user, Some(new_email), lifetime).await?;
+    let link = format!("{}/verify-email?token={}", public_url, token);
+    let email = mail::Email {
+        to: new_email.to_string(),
+        subject: "Confirm your new email address".to_string(),
+        body: format!(
+            "Follow this link within {} hours to use this address for your account:\n\n{}\n\n\
+             If you did not ask for this, ignore this email.",
+            lifetime / 3600,
+            link
+        ),
+    };
+    context.global.mail.send(email).await
+}
+/* Signing up or moving to a registered address answers like any other, */
+/* only its owner learns of the attempt. */
+async fn notify_registered(context: &graphql::JuniperContext, owner: &User) {
+    let email = mail::Email {
+        to: owner.email.clone(),
+        subject: "Your email address is already registered".to_string(),
+        body: "Someone tried to use this email address for another account. If it was you, \
+               log in or reset your password instead.\n\n\
+               If it was not, ignore this email, your account is unchanged."
+            .to_string(),
+    };
+    if let Err(error) = context.global.mail.send(email).await {
+        crate::console_error!("Failed to send registered address email: {}", error);
+    }
+}
+
 pub struct Mutation;
 #[juniper::graphql_object(context = graphql::JuniperContext)]
 impl Mutation {
+    /* Answers the same way whether or not the email is registered, the */
+    /* inbox tells the rest. Each attempt sends an email, hence the limit. */
     pub async fn create_user(
         email: String,
         password: String,
         context: &graphql::JuniperContext,
-    ) -> juniper::FieldResult<User> {
-        use scrypt::password_hash::PasswordHasher;
-
-        let id = format!("{}{}", User::prefix(), util::uuid());
-        let query = format!(
-            "@email:{{{}}}",
-            email.replace("@", "\\@").replace(".", "\\.")
-        );
-        let mut redis_search = context.global.redis.search().await?;
-        let search_result = redis_search.search(User::index_name(), query, None).await?;
-        if search_result.results.len() > 0 {
-            let message = format!("Email {} is already in use", email);
-            return Err(error::Error::new_string(message).into());
+    ) -> juniper::FieldResult<bool> {
+        mail::validate(email.as_str())?;
+        rate_limit(context, "verify").await?;
+        /* Hashed either way, so timing does not tell the cases apart. */
+        let hashed_password =
+            auth::password::hash(&context.global.config.auth.password, password.as_str())?;
+        if let Some(owner) = User::find_by_email(&context.global, email.as_str()).await? {
+            notify_registered(context, &owner).await;
+            return Ok(true);
         }
 
-        let hashed_password = scrypt::Scrypt
-            .hash_password(password.as_bytes(), &context.global.auth.salt)?
-            .to_string() as String;
+        let id = format!("{}{}", User::prefix(), util::uuid());
 
         let user = User {
             id: id.clone().into(),
             email,
             password: hashed_password,
+            email_verified: false,
+            two_factor: None,
+            roles: vec![Role::User],
+            identities: vec![],
 
-            jti: None,
             sub: id.clone(),
         };
         let mut redis_json = context.global.redis.json().await?;
@@ -160,71 +733,488 @@ impl Mutation {
             .set(id, "$".into(), serde_json::to_string(&user)?, None)
             .await?;
 
-        Ok(user)
+        /* The account exists either way, the link can be requested again. */
+        if let Err(error) = send_verification(context, &user).await {
+            crate::console_error!("Failed to send verification email: {}", error);
+        }
+
+        Ok(true)
     }
     pub async fn login_user(
         email: String,
         password: String,
         context: &graphql::JuniperContext,
     ) -> juniper::FieldResult<String> {
-        use scrypt::password_hash::PasswordVerifier;
+        rate_limit(context, "login").await?;
+        let address = locked_out(context, email.as_str()).await?;
 
-        let query = format!(
-            "@email:{{{}}}",
-            email.replace("@", "\\@").replace(".", "\\.")
-        );
-        let mut redis_search = context.global.redis.search().await?;
-        let search_result = redis_search.search(User::index_name(), query, None).await?;
-        if search_result.results.len() > 1 {
-            let message = format!("More than one user found with email {}", email);
+        /* Unknown emails fail exactly like wrong passwords, hashing included, */
+        /* so the answer does not reveal which accounts exist. */
+        let config = &context.global.config.auth.password;
+        let user = User::find_by_email(&context.global, email.as_str()).await?;
+        let verified = match &user {
+            Some(user) => auth::password::verify(password.as_str(), user.password.as_str())?,
+            None => {
+                auth::password::hash(config, password.as_str())?;
+                false
+            }
+        };
+        let user = match (user, verified) {
+            (Some(user), true) => user,
+            _ => {
+                auth::lockout::fail(&context.global, email.as_str(), address).await?;
+                return Err(error::Error::new_str("Incorrect email or password").into());
+            }
+        };
+        /* Upgrade hashes made with an older algorithm or cost. */
+        if auth::password::outdated(config, user.password.as_str())? {
+            if let Err(error) =
+                User::set_password(&context.global, user.sub.as_str(), password.as_str()).await
+            {
+                crate::console_warn!("Failed to rehash password: {}", error);
+            }
+        }
+        let verification = context.global.config.auth.verification;
+        if !user.email_verified && verification == config::Verification::Required {
+            let message = format!("Email {} is not verified", email);
             return Err(error::Error::new_string(message).into());
         }
-        match search_result.results.first() {
-            Some(result) => {
-                let user = serde_json::from_str::<User>(result.value.as_str())?;
-                let parsed_hash = scrypt::password_hash::PasswordHash::new(user.password.as_str())?;
-                if scrypt::Scrypt
-                    .verify_password(password.as_bytes(), &parsed_hash)
-                    .is_err()
-                {
-                    let message = format!("Incorrect password for user with email {}", email);
-                    Err(error::Error::new_string(message).into())
-                } else {
-                    let claims = jwt::Payload {
-                        id: user.id,
-                        jti: user.jti,
-                        email: user.email,
-                    };
-                    let token = {
-                        let mut message = context.message.try_write()?;
-                        context
-                            .global
-                            .auth
-                            .refresh
-                            .create(claims.clone(), &mut message)?;
-                        context
-                            .global
-                            .auth
-                            .access
-                            .create(claims.clone(), &mut message)?
-                    };
-                    Ok(token)
+        /* With two-factor authentication the password only earns a challenge. */
+        if user.two_factor.is_some() {
+            let lifetime = context.global.config.auth.totp.challenge_lifetime;
+            let challenge =
+                auth::ticket::issue(&context.global, "challenge", user.sub.as_str(), lifetime)
+                    .await?;
+            let mut extensions = juniper::Object::with_capacity(1);
+            extensions.add_field("challenge", juniper::Value::scalar(challenge));
+            return Err(juniper::FieldError::new(
+                "Two-factor code required",
+                juniper::Value::object(extensions),
+            ));
+        }
+        auth::lockout::succeed(&context.global, email.as_str()).await?;
+        Ok(issue_tokens(context, user).await?)
+    }
+    /* Second login step, a wrong code spends the challenge. */
+    pub async fn login_user_two_factor(
+        challenge: String,
+        code: String,
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<String> {
+        rate_limit(context, "login").await?;
+
+        let sub =
+            match auth::ticket::redeem(&context.global, "challenge", challenge.as_str()).await? {
+                Some(sub) => sub,
+                None => {
+                    let message = "Invalid or expired login challenge";
+                    return Err(error::Error::new_str(message).into());
                 }
+            };
+        let user = User::find(&context.global, sub.as_str()).await?;
+        let address = locked_out(context, user.email.as_str()).await?;
+        if !check_two_factor(&context.global, &user, code.as_str()).await? {
+            auth::lockout::fail(&context.global, user.email.as_str(), address).await?;
+            let message = "Incorrect two-factor code, log in again";
+            return Err(error::Error::new_str(message).into());
+        }
+        auth::lockout::succeed(&context.global, user.email.as_str()).await?;
+        Ok(issue_tokens(context, user).await?)
+    }
+    pub async fn enroll_totp(
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<TotpEnrollment> {
+        let user = current_user(context, Interactive).await?;
+        if user.two_factor.is_some() {
+            let message = "Two-factor authentication is already enabled";
+            return Err(error::Error::new_str(message).into());
+        }
+        let config = &context.global.config.auth.totp;
+        let secret = totp::secret();
+        let mut redis_main = context.global.redis.main().await?;
+        redis_main
+            .set_ex::<_, _, ()>(
+                format!("totp:pending:{}", user.sub),
+                secret.as_str(),
+                config.enrollment_lifetime,
+            )
+            .await?;
+        let enrollment = TotpEnrollment {
+            uri: totp::uri(config, user.email.as_str(), secret.as_str()),
+            secret,
+        };
+        Ok(enrollment)
+    }
+    /* Enables the pending secret once the app shows a matching code, and */
+    /* returns recovery codes, which are never shown again. */
+    pub async fn confirm_totp(
+        code: String,
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<Vec<String>> {
+        let user = current_user(context, Interactive).await?;
+        let key = format!("totp:pending:{}", user.sub);
+        let mut redis_main = context.global.redis.main().await?;
+        let secret = match redis_main.get::<_, Option<String>>(key.as_str()).await? {
+            Some(secret) => secret,
+            None => {
+                let message = "No pending two-factor enrollment";
+                return Err(error::Error::new_str(message).into());
             }
+        };
+        let config = &context.global.config.auth.totp;
+        let last_step = match totp::verify(config, secret.as_str(), code.as_str(), 0)? {
+            Some(step) => step,
+            None => return Err(error::Error::new_str("Incorrect two-factor code").into()),
+        };
+        let (codes, hashes) = totp::recovery_codes(RECOVERY_CODES);
+        let two_factor = TwoFactor {
+            secret,
+            recovery_codes: hashes,
+            last_step,
+        };
+        User::set_two_factor(&context.global, user.sub.as_str(), Some(&two_factor)).await?;
+        redis_main.del::<_, ()>(key).await?;
+        Ok(codes)
+    }
+    pub async fn regenerate_recovery_codes(
+        code: String,
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<Vec<String>> {
+        let user = current_user(context, Interactive).await?;
+        if !check_two_factor(&context.global, &user, code.as_str()).await? {
+            return Err(error::Error::new_str("Incorrect two-factor code").into());
+        }
+        /* Reload, checking the code may have moved the last step on. */
+        let user = User::find(&context.global, user.sub.as_str()).await?;
+        let mut two_factor = match user.two_factor {
+            Some(two_factor) => two_factor,
             None => {
-                let message = format!("No user found with email {}", email);
-                Err(error::Error::new_string(message).into())
+                return Err(error::Error::new_str("Two-factor authentication is disabled").into())
+            }
+        };
+        let (codes, hashes) = totp::recovery_codes(RECOVERY_CODES);
+        two_factor.recovery_codes = hashes;
+        User::set_two_factor(&context.global, user.sub.as_str(), Some(&two_factor)).await?;
+        Ok(codes)
+    }
+    /* Needs the password as well, a code alone may come from a stolen */
+    /* device or a leaked recovery code. */
+    pub async fn disable_totp(
+        code: String,
+        password: String,
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<bool> {
+        let user = current_user(context, Interactive).await?;
+        confirm_password(context, &user, password.as_str()).await?;
+        if !check_two_factor(&context.global, &user, code.as_str()).await? {
+            return Err(error::Error::new_str("Incorrect two-factor code").into());
+        }
+        User::set_two_factor(&context.global, user.sub.as_str(), None).await?;
+        Ok(true)
+    }
+    pub async fn request_password_reset(
+        email: String,
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<bool> {
+        rate_limit(context, "reset").await?;
+        let public_url = auth::util::public_url(&context.global)?;
+
+        /* Answer the same way whether or not the email is registered. */
+        if let Some(user) = User::find_by_email(&context.global, email.as_str()).await? {
+            let lifetime = context.global.config.auth.reset_lifetime;
+            let token =
+                issue_mailed_ticket(&context.global, "reset", &user, None, lifetime).await?;
+            let link = format!("{}/reset-password?token={}", public_url, token);
+            let email = mail::Email {
+                to: user.email,
+                subject: "Reset your password".to_string(),
+                body: format!(
+                    "Follow this link within {} minutes to choose a new password:\n\n{}\n\n\
+                     If you did not ask to reset your password, ignore this email.",
+                    lifetime / 60,
+                    link
+                ),
+            };
+            if let Err(error) = context.global.mail.send(email).await {
+                crate::console_error!("Failed to send password reset email: {}", error);
+            }
+        }
+        Ok(true)
+    }
+    pub async fn reset_password(
+        token: String,
+        new_password: String,
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<bool> {
+        let sub = match redeem_mailed_ticket(&context.global, "reset", token.as_str()).await? {
+            Some((user, _new_email)) => user.sub,
+            None => {
+                let message = "Invalid or expired password reset token";
+                return Err(error::Error::new_str(message).into());
+            }
+        };
+        User::set_password(&context.global, sub.as_str(), new_password.as_str()).await?;
+        /* Sign out everywhere and drop the API keys, whoever knew the old */
+        /* password may have made some. */
+        auth::revocation::revoke(&context.global, sub.as_str()).await?;
+        auth::api_key::revoke_all(&context.global, sub.as_str()).await?;
+        Ok(true)
+    }
+    /* Verifies the address, or moves the account to the new address an */
+    /* email change was mailed to. */
+    pub async fn verify_email(
+        token: String,
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<bool> {
+        let invalid = || {
+            let message = "Invalid or expired email verification token";
+            Err(error::Error::new_str(message).into())
+        };
+        let redeemed = match redeem_mailed_ticket(&context.global, "verify", token.as_str()).await?
+        {
+            Some(redeemed) => Some(redeemed),
+            None => redeem_mailed_ticket(&context.global, "change", token.as_str()).await?,
+        };
+        let (user, new_email) = match redeemed {
+            Some(redeemed) => redeemed,
+            None => return invalid(),
+        };
+        let mut redis_json = context.global.redis.json().await?;
+        /* The users index tags $.email, so RediSearch picks the new address */
+        /* up as soon as the document changes. */
+        if let Some(new_email) = new_email {
+            if User::find_by_email(&context.global, new_email.as_str())
+                .await?
+                .is_some()
+            {
+                return invalid();
             }
+            redis_json
+                .set(
+                    user.sub.clone(),
+                    "$.email".into(),
+                    serde_json::to_string(&new_email)?,
+                    None,
+                )
+                .await?;
         }
+        redis_json
+            .set(
+                user.sub,
+                "$.email_verified".into(),
+                "true".to_string(),
+                None,
+            )
+            .await?;
+        Ok(true)
+    }
+    pub async fn resend_verification(
+        email: String,
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<bool> {
+        rate_limit(context, "verify").await?;
+
+        /* Answer the same way whether or not the email is registered. */
+        if let Some(user) = User::find_by_email(&context.global, email.as_str()).await? {
+            if !user.email_verified {
+                if let Err(error) = send_verification(context, &user).await {
+                    crate::console_error!("Failed to send verification email: {}", error);
+                }
+            }
+        }
+        Ok(true)
+    }
+    pub async fn unlock_account(
+        email: String,
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<bool> {
+        context
+            .authorize(HasPermission(Permission::ManageUsers))
+            .await?;
+        auth::lockout::unlock(&context.global, email.as_str()).await?;
+        Ok(true)
     }
     pub async fn logout_user(context: &graphql::JuniperContext) -> juniper::FieldResult<bool> {
+        let (refresh, issuer) = {
+            let message = context.message.try_read()?;
+            let refresh = message
+                .cookies
+                .get("refresh")
+                .map(|cookie| cookie.value().to_string());
+            (refresh, auth::util::issuer(&message, &context.global))
+        };
+        if let Some(refresh) = refresh {
+            auth::revocation::revoke_token(&context.global, refresh, issuer.as_str()).await?;
+        }
         {
             let mut message = context.message.try_write()?;
             context.global.auth.refresh.reset(&mut message);
         }
         Ok(true)
     }
-    /* @todo: implement revoke_user(id, context) -> bool */
+    /* Signs out every other session and returns a new access token for */
+    /* this one. */
+    pub async fn change_password(
+        current_password: String,
+        new_password: String,
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<String> {
+        let user = current_user(context, Interactive).await?;
+        confirm_password(context, &user, current_password.as_str()).await?;
+        User::set_password(&context.global, user.sub.as_str(), new_password.as_str()).await?;
+        auth::revocation::revoke(&context.global, user.sub.as_str()).await?;
+        let user = User::find(&context.global, user.sub.as_str()).await?;
+        Ok(issue_tokens(context, user).await?)
+    }
+    /* The email changes once the link mailed to the new address is */
+    /* followed, so the answer never tells whether it is registered. */
+    pub async fn change_email(
+        new_email: String,
+        password: String,
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<bool> {
+        mail::validate(new_email.as_str())?;
+        let user = current_user(context, Interactive).await?;
+        confirm_password(context, &user, password.as_str()).await?;
+        match User::find_by_email(&context.global, new_email.as_str()).await? {
+            Some(owner) if owner.sub == user.sub => {}
+            Some(owner) => notify_registered(context, &owner).await,
+            None => send_email_change(context, &user, new_email.as_str()).await?,
+        }
+        Ok(true)
+    }
+    /* Users delete their own account with their password, user managers */
+    /* delete any account without one. */
+    pub async fn delete_user(
+        id: juniper::ID,
+        password: Option<String>,
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<bool> {
+        let claims = context
+            .authorize(
+                Interactive
+                    .and(Subject(&id))
+                    .or(HasPermission(Permission::ManageUsers)),
+            )
+            .await?;
+        let user = User::find(&context.global, &id).await?;
+        let own = claims.sub == user.sub;
+        if own {
+            let password = password.unwrap_or_default();
+            confirm_password(context, &user, password.as_str()).await?;
+        }
+        let mut redis_json = context.global.redis.json().await?;
+        redis_json.del(user.sub.clone(), None).await?;
+        let mut redis_main = context.global.redis.main().await?;
+        let mut keys = user
+            .identities
+            .iter()
+            .map(LinkedIdentity::key)
+            .collect::<Vec<_>>();
+        keys.push(format!("totp:pending:{}", user.sub));
+        redis_main.del::<_, ()>(keys).await?;
+        /* Access tokens stay valid until they expire, but no longer resolve */
+        /* to a user. */
+        auth::revocation::revoke(&context.global, user.sub.as_str()).await?;
+        auth::api_key::revoke_all(&context.global, user.sub.as_str()).await?;
+        if own {
+            let mut message = context.message.try_write()?;
+            context.global.auth.refresh.reset(&mut message);
+        }
+        Ok(true)
+    }
+    /* Keys get at most the permissions of their user, and cannot be used */
+    /* to create further keys. */
+    pub async fn create_api_key(
+        name: String,
+        scopes: Option<Vec<Permission>>,
+        expires: Option<chrono::DateTime<chrono::Utc>>,
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<CreatedApiKey> {
+        let claims = context.authorize(Authenticated).await?;
+        let scopes = scopes.unwrap_or_default();
+        if claims.ajd.scopes.is_some() {
+            return Err(error::Error::new_str("API keys cannot create API keys").into());
+        }
+        if let Some(scope) = scopes.iter().find(|scope| !claims.ajd.permits(**scope)) {
+            let message = format!("Not authorized to grant {:?}", scope);
+            return Err(error::Error::new_string(message).into());
+        }
+        let expires = expires.map(|expires| expires.timestamp().max(0) as u64);
+        let (key, api_key) = auth::api_key::create(
+            &context.global,
+            claims.sub.as_str(),
+            name.as_str(),
+            &scopes,
+            expires,
+        )
+        .await?;
+        let created = CreatedApiKey {
+            key,
+            api_key: api_key.into(),
+        };
+        Ok(created)
+    }
+    pub async fn revoke_api_key(
+        id: juniper::ID,
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<bool> {
+        let claims = context.authorize(Interactive).await?;
+        if !auth::api_key::revoke(&context.global, claims.sub.as_str(), &id).await? {
+            let message = format!("No API key with id {}", id);
+            return Err(error::Error::new_string(message).into());
+        }
+        Ok(true)
+    }
+    /* Signs one device out, its access token lasts until it expires. */
+    pub async fn revoke_session(
+        id: juniper::ID,
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<bool> {
+        let claims = context.authorize(Interactive).await?;
+        let revoked =
+            auth::revocation::revoke_session(&context.global, claims.sub.as_str(), &id).await?;
+        if !revoked {
+            let message = format!("No session with id {}", id);
+            return Err(error::Error::new_string(message).into());
+        }
+        Ok(true)
+    }
+    /* Takes effect as the user's access tokens are refreshed. */
+    pub async fn set_user_roles(
+        id: juniper::ID,
+        roles: Vec<Role>,
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<User> {
+        context
+            .authorize(HasPermission(Permission::ManageUsers))
+            .await?;
+        let user = User::find(&context.global, &id).await?;
+        let mut redis_json = context.global.redis.json().await?;
+        redis_json
+            .set(
+                user.sub.clone(),
+                "$.roles".into(),
+                serde_json::to_string(&roles)?,
+                None,
+            )
+            .await?;
+        Ok(User { roles, ..user })
+    }
+    pub async fn revoke_user(
+        id: juniper::ID,
+        context: &graphql::JuniperContext,
+    ) -> juniper::FieldResult<bool> {
+        context
+            .authorize(
+                Interactive
+                    .and(Subject(&id))
+                    .or(HasPermission(Permission::ManageUsers)),
+            )
+            .await?;
+        auth::revocation::revoke(&context.global, id.to_string().as_str()).await?;
+        Ok(true)
+    }
 }
 impl Mutation {
     pub fn new() -> Self {
@@ -233,3 +1223,47 @@ impl Mutation {
 }
 
 pub type Subscription = juniper::EmptySubscription<graphql::JuniperContext>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn run(
+        redis_main: &mut ::redis::aio::MultiplexedConnection,
+        script: &str,
+        key: &str,
+        args: &[&str],
+    ) -> bool {
+        let script = ::redis::Script::new(script);
+        let mut invocation = script.key(key);
+        for arg in args {
+            invocation.arg(*arg);
+        }
+        invocation.invoke_async(redis_main).await.unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis with RedisJSON, set REDIS_URL"]
+    async fn spends_two_factor_codes_once() {
+        let mut redis_main = crate::core::redis::RedisContext::test().await;
+        let key = format!("test:two_factor:{}", util::now().unwrap());
+        let document = r#"{"two_factor":{"secret":"S","recovery_codes":["a","b"],"last_step":10}}"#;
+        ::redis::cmd("JSON.SET")
+            .arg(&key)
+            .arg("$")
+            .arg(document)
+            .query_async::<_, ()>(&mut redis_main)
+            .await
+            .unwrap();
+
+        assert!(run(&mut redis_main, TOTP_STEP_SCRIPT, &key, &["S", "11"]).await);
+        assert!(!run(&mut redis_main, TOTP_STEP_SCRIPT, &key, &["S", "11"]).await);
+        assert!(!run(&mut redis_main, TOTP_STEP_SCRIPT, &key, &["T", "12"]).await);
+
+        assert!(run(&mut redis_main, RECOVERY_CODE_SCRIPT, &key, &["b"]).await);
+        assert!(!run(&mut redis_main, RECOVERY_CODE_SCRIPT, &key, &["b"]).await);
+        assert!(run(&mut redis_main, RECOVERY_CODE_SCRIPT, &key, &["a"]).await);
+
+        redis_main.del::<_, ()>(&key).await.unwrap();
+    }
+}
@@ -1,20 +1,24 @@
 mod core;
 mod custom;
 
-#[cfg(debug_assertions)]
-async fn __main() -> Result<(), core::error::Error> {
-    let context = core::context::Context::new()?;
-    custom::redis::index(&context).await?;
-    let mut server = core::server::Server::new(context, [127, 0, 0, 1]);
-    server.serve().await;
-    Ok(())
-}
-#[cfg(not(debug_assertions))]
+use structopt::StructOpt;
+
 async fn __main() -> Result<(), core::error::Error> {
-    let context = core::context::Context::new()?;
-    custom::redis::index(&context).await?;
-    let mut server = core::server::Server::new(context, [0, 0, 0, 0]);
-    server.serve().await;
+    let arguments = core::config::Arguments::from_args();
+    let command = arguments.command;
+    let config = core::config::Config::load(arguments)?;
+    match command {
+        Some(core::config::Command::RotateKeys) => {
+            let redis = core::redis::RedisContext::new(&config.redis)?;
+            core::auth::AuthContext::rotate(&config.auth, &redis).await?;
+        }
+        None => {
+            let context = core::context::Context::new(config).await?;
+            custom::redis::index(&context).await?;
+            let mut server = core::server::Server::new(context);
+            server.serve().await;
+        }
+    }
     Ok(())
 }
 